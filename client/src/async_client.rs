@@ -0,0 +1,296 @@
+use std::io;
+use std::time::{Duration, Instant};
+use futures_util::{SinkExt, StreamExt};
+use log::{error, info, warn};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::time::{interval, sleep};
+use tokio_util::codec::Framed;
+use config::{DisconnectReason, EncryptionKey, FrameDecoder, FrameEncoder, Packet, PacketCodec, AUTH_FAILED_TOKEN, SUPPORTED_VERSIONS};
+use crate::outbox::Outbox;
+use crate::settings::Settings;
+use crate::{auth_request, fits_version, parse_input, print_chat, Ping, INPUT_HELP};
+
+type Connection = Framed<TcpStream, PacketCodec>;
+
+// Lines typed or packets received that nobody has taken yet.
+const CHANNEL_CAPACITY: usize = 64;
+
+// What run_client reports to the application driving it.
+#[derive(Debug)]
+pub enum ClientEvent {
+    // Logged in for the first time. Reconnects resume the session quietly.
+    Ready,
+    // Game data and chat from the server, everything else is handled by the client.
+    Received(Packet),
+}
+
+enum ConnectionEnd {
+    Lost,
+    // The server is shutting down, reconnecting would not help.
+    ServerShutdown,
+    // The application dropped its end of the input or the events.
+    Stopped,
+    // The server closes the connection, the next one logs in rather than resuming.
+    SessionExpired,
+}
+
+// Same behaviour as the threaded client, on the terminal. Stdin gets its own task because reading it blocks.
+pub async fn client(settings: Settings) -> io::Result<()> {
+    let (input_sender, input) = mpsc::channel(CHANNEL_CAPACITY);
+    let (event_sender, mut events) = mpsc::channel(CHANNEL_CAPACITY);
+    tokio::spawn(async move {
+        while let Some(event) = events.recv().await {
+            match event {
+                ClientEvent::Ready => {
+                    tokio::spawn(input_task(input_sender.clone()));
+                }
+                ClientEvent::Received(Packet::GameData(game_data)) => println!("Game data: {}", game_data.value),
+                ClientEvent::Received(Packet::ChatMessage { recipient, sender, timestamp, text }) => {
+                    print_chat(recipient.as_deref(), &sender, timestamp, &text);
+                }
+                ClientEvent::Received(_) => {}
+            }
+        }
+    });
+    run_client(settings, input, event_sender).await
+}
+
+// Connects, logs in and keeps the session alive until the server shuts down or the application hangs up.
+// Packets from input go to the server, sequenced so none is lost to a reconnect. Reading, pinging and
+// sending are branches of one select loop per connection.
+pub async fn run_client(settings: Settings, mut input: Receiver<Packet>, events: Sender<ClientEvent>) -> io::Result<()> {
+    let mut outbox = Outbox::new();
+    let mut token = None;
+    let mut reconnecting = false;
+    loop {
+        if reconnecting {
+            info!("Reconnecting...");
+        }
+        let stream = match TcpStream::connect(settings.server_address).await {
+            Ok(stream) => stream,
+            Err(_e) if reconnecting => {
+                warn!("Failed to reconnect.");
+                sleep(settings.reconnect_delay()).await;
+                continue;
+            }
+            Err(_e) => {
+                error!("Connection failed!");
+                return Ok(());
+            }
+        };
+        if !reconnecting {
+            info!("Connected to server.");
+        }
+        let codec = PacketCodec::new(
+            FrameDecoder::with_max_payload_size(settings.max_payload_size).with_key(EncryptionKey::from_env()).with_resync(true),
+            FrameEncoder::new().with_key(EncryptionKey::from_env()).with_checksum(true),
+        );
+        let mut connection = Framed::new(stream, codec);
+        match initialize_connection(&mut connection, token.take(), &settings).await {
+            Some(new_token) => token = Some(new_token),
+            None if reconnecting => {
+                warn!("Could not restore the session.");
+                sleep(settings.reconnect_delay()).await;
+                continue;
+            }
+            None => return Ok(()),
+        }
+        if reconnecting {
+            // Anything queued for the old stream is gone, unacknowledged packets go out again in order.
+            for packet in outbox.unacknowledged() {
+                connection.feed(packet.clone()).await?;
+            }
+            connection.flush().await?;
+            info!("Reconnected!");
+        } else {
+            info!("Finished initialization");
+            if events.send(ClientEvent::Ready).await.is_err() {
+                return Ok(());
+            }
+        }
+        match run_connection(&mut connection, &mut outbox, &mut input, &events, settings.ping_interval()).await {
+            ConnectionEnd::Lost => warn!("Connection lost. Retrying..."),
+            ConnectionEnd::SessionExpired => token = None,
+            ConnectionEnd::ServerShutdown | ConnectionEnd::Stopped => return Ok(()),
+        }
+        reconnecting = true;
+    }
+}
+
+async fn run_connection(connection: &mut Connection, outbox: &mut Outbox, input: &mut Receiver<Packet>, events: &Sender<ClientEvent>, ping_interval: Duration) -> ConnectionEnd {
+    let mut ping = Ping::new(Instant::now());
+    let mut ping_interval = interval(ping_interval);
+    loop {
+        tokio::select! {
+            frame = connection.next() => {
+                let packet = match frame {
+                    Some(Ok(Ok((_, packet)))) => packet,
+                    Some(Ok(Err(e))) => {
+                        warn!("Dropped malformed frame: {}", e);
+                        continue;
+                    }
+                    Some(Err(_)) | None => return ConnectionEnd::Lost,
+                };
+                match packet {
+                    Packet::Ping => {
+                        ping.receive();
+                        info!("Ping: {:?}", ping.get_duration().unwrap());
+                    }
+                    Packet::Disconnect { reason: DisconnectReason::ServerShutdown } => {
+                        info!("Server is shutting down.");
+                        return ConnectionEnd::ServerShutdown;
+                    }
+                    Packet::Disconnect { reason: DisconnectReason::SessionExpired } => {
+                        info!("Session expired, logging in again.");
+                        return ConnectionEnd::SessionExpired;
+                    }
+                    Packet::Disconnect { reason } => {
+                        info!("Disconnected by the server ({:?}).", reason);
+                    }
+                    Packet::Ack { sequence } => {
+                        outbox.acknowledge(sequence);
+                    }
+                    Packet::Heartbeat => {
+                        if connection.send(Packet::Heartbeat).await.is_err() {
+                            return ConnectionEnd::Lost;
+                        }
+                    }
+                    packet @ (Packet::GameData(_) | Packet::ChatMessage { .. }) => {
+                        if events.send(ClientEvent::Received(packet)).await.is_err() {
+                            return ConnectionEnd::Stopped;
+                        }
+                    }
+                    unexpected_value => {
+                        warn!("Unexpected data type {:?}", unexpected_value.data_type());
+                    }
+                }
+            }
+            _ = ping_interval.tick() => {
+                if ping.received_at.is_some() {
+                    ping = Ping::new(Instant::now());
+                }
+                if connection.send(Packet::Ping).await.is_err() {
+                    return ConnectionEnd::Lost;
+                }
+            }
+            packet = input.recv() => {
+                let Some(packet) = packet else {
+                    return ConnectionEnd::Stopped;
+                };
+                let version = connection.codec().encoder().version();
+                if !fits_version(&packet, version) {
+                    continue;
+                }
+                let packet = outbox.wrap(packet, version);
+                if let Err(e) = connection.send(packet).await {
+                    warn!("Could not send message: {}", e);
+                    return ConnectionEnd::Lost;
+                }
+            }
+        }
+    }
+}
+
+// The connection checks what it is given against the protocol version it agreed on.
+async fn input_task(input: Sender<Packet>) {
+    println!("{}", INPUT_HELP);
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let Some(packet) = parse_input(&line) else {
+            continue;
+        };
+        if input.send(packet).await.is_err() {
+            break;
+        }
+    }
+}
+
+async fn wait_for_packet(connection: &mut Connection) -> Option<Packet> {
+    match connection.next().await {
+        Some(Ok(Ok((_, packet)))) => Some(packet),
+        Some(Ok(Err(e))) => {
+            warn!("Malformed frame from server: {}", e);
+            None
+        }
+        Some(Err(_e)) => {
+            warn!("Could not read from server");
+            None
+        }
+        None => {
+            warn!("Server closed the connection.");
+            None
+        }
+    }
+}
+
+async fn negotiate_version(connection: &mut Connection) -> bool {
+    if connection.send(Packet::Hello { versions: SUPPORTED_VERSIONS.to_vec() }).await.is_err() {
+        return false;
+    }
+    match wait_for_packet(connection).await {
+        Some(Packet::HelloAck { version, challenge }) if SUPPORTED_VERSIONS.contains(&version) => {
+            info!("Using protocol version {}", version);
+            connection.codec_mut().encoder_mut().set_version(version);
+            connection.codec_mut().bind_to(&challenge);
+            true
+        }
+        Some(Packet::HelloAck { version, .. }) => {
+            error!("Server picked unsupported protocol version {}", version);
+            false
+        }
+        Some(Packet::HelloReject { versions }) => {
+            error!("Server rejected our protocol versions {:?}, it supports {:?}", SUPPORTED_VERSIONS, versions);
+            false
+        }
+        Some(unexpected_value) => {
+            warn!("Data type unknown {:?}", unexpected_value.data_type());
+            false
+        }
+        None => false,
+    }
+}
+
+async fn wait_for_auth_response(connection: &mut Connection) -> Option<String> {
+    loop {
+        match wait_for_packet(connection).await {
+            Some(Packet::AuthResponse { failure: Some(failure), .. }) => {
+                error!("Authentication failed: {}", failure);
+                return None;
+            }
+            Some(Packet::AuthResponse { token, .. }) if token == AUTH_FAILED_TOKEN => {
+                error!("Authentication failed.");
+                return None;
+            }
+            Some(Packet::AuthResponse { token, .. }) => return Some(token),
+            Some(unexpected_value) => {
+                warn!("Data type unknown {:?}", unexpected_value.data_type());
+            }
+            None => {
+                error!("Could not authenticate");
+                return None;
+            }
+        }
+    }
+}
+
+// Resumes the previous session when there is one and falls back to logging in again.
+// Returns the session token.
+async fn initialize_connection(connection: &mut Connection, previous_token: Option<String>, settings: &Settings) -> Option<String> {
+    if !negotiate_version(connection).await {
+        return None;
+    }
+    if let Some(token) = previous_token {
+        connection.send(Packet::ResumeSession { token }).await.ok()?;
+        if let Some(token) = wait_for_auth_response(connection).await {
+            info!("Resumed session.");
+            return Some(token);
+        }
+    }
+    connection.send(auth_request(settings)).await.ok()?;
+    info!("Waiting for authentication.");
+    let token = wait_for_auth_response(connection).await?;
+    info!("Authenticated!");
+    Some(token)
+}
@@ -0,0 +1,99 @@
+// The client as a library. With the async feature other services drive it through async_client::run_client,
+// the binary only adds the command line and the terminal on top.
+#[cfg(feature = "async")]
+pub mod async_client;
+mod outbox;
+pub mod settings;
+#[cfg(not(feature = "async"))]
+pub mod threaded;
+
+use std::time::{Duration, Instant};
+use log::warn;
+use config::{GameData, Packet, FIXED_LAYOUT_VERSION, MAX_CHAT_MESSAGE_LENGTH};
+use settings::Settings;
+
+struct Ping {
+    pub sent_at: Instant,
+    pub received_at: Option<Instant>,
+
+}
+
+impl Ping {
+    fn new(sent_at: Instant) -> Self {
+        Ping {
+            sent_at,
+            received_at: None,
+        }
+    }
+
+    fn receive(&mut self) {
+        if self.received_at.is_none() {
+            self.received_at = Some(Instant::now());
+        }
+    }
+
+    fn get_duration(&self) -> Option<Duration> {
+        self.received_at.map(|received_at| received_at.duration_since(self.sent_at))
+    }
+}
+
+fn auth_request(settings: &Settings) -> Packet {
+    Packet::AuthRequest {
+        username: settings.username.clone(),
+        password: settings.password.clone(),
+    }
+}
+
+const INPUT_HELP: &str = "Type a number to send it as game data, /msg <user> <text> to message one user, anything else to chat with everyone.";
+
+// A number is game data, /msg user text goes to one user and any other line to everyone.
+// None when there is nothing to send, the reason is already printed.
+fn parse_input(line: &str) -> Option<Packet> {
+    let line = line.trim();
+    if line.is_empty() {
+        return None;
+    }
+    if let Ok(value) = line.parse::<u16>() {
+        return Some(Packet::GameData(GameData::new(value)));
+    }
+    let (recipient, text) = match line.strip_prefix("/msg") {
+        Some(rest) if rest.is_empty() || rest.starts_with(char::is_whitespace) => match rest.trim().split_once(char::is_whitespace) {
+            Some((recipient, text)) => (Some(recipient.to_string()), text.trim()),
+            None => {
+                println!("Usage: /msg <user> <text>");
+                return None;
+            }
+        },
+        _ => (None, line),
+    };
+    if text.len() > MAX_CHAT_MESSAGE_LENGTH {
+        println!("Messages can be at most {} bytes long.", MAX_CHAT_MESSAGE_LENGTH);
+        return None;
+    }
+    Some(Packet::ChatMessage {
+        recipient,
+        sender: String::new(),
+        timestamp: 0,
+        text: text.to_string(),
+    })
+}
+
+// Only game data fits the fixed layout, chat needs the length prefixed one.
+fn fits_version(packet: &Packet, version: u8) -> bool {
+    if version == FIXED_LAYOUT_VERSION && packet.encode_fixed().is_err() {
+        warn!("Chat needs protocol version 2, the server only agreed on version 1.");
+        return false;
+    }
+    true
+}
+
+// Times are shown in UTC.
+fn print_chat(recipient: Option<&str>, sender: &str, timestamp: u64, text: &str) {
+    let seconds_of_day = timestamp % (24 * 60 * 60);
+    let time = format!("{:02}:{:02}:{:02}", seconds_of_day / 3600, seconds_of_day / 60 % 60, seconds_of_day % 60);
+    match (sender, recipient) {
+        ("", _) => println!("[{}] server: {}", time, text),
+        (sender, Some(recipient)) => println!("[{}] {} -> {}: {}", time, sender, recipient, text),
+        (sender, None) => println!("[{}] {}: {}", time, sender, text),
+    }
+}
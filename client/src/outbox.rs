@@ -0,0 +1,45 @@
+use std::collections::VecDeque;
+use config::{Packet, FIXED_LAYOUT_VERSION};
+
+// Application packets are numbered and kept until the server acknowledges them.
+pub struct Outbox {
+    epoch: u32,
+    next_sequence: u32,
+    unacknowledged: VecDeque<(u32, Packet)>,
+}
+
+impl Outbox {
+    pub fn new() -> Self {
+        Outbox {
+            epoch: rand::random(),
+            next_sequence: 1,
+            unacknowledged: VecDeque::new(),
+        }
+    }
+
+    // Peers on the fixed layout cannot carry sequence numbers and get the packet as is.
+    // Game data and chat are numbered, so neither is lost to a reconnect. The server acknowledges nothing else.
+    pub fn wrap(&mut self, packet: Packet, version: u8) -> Packet {
+        if version == FIXED_LAYOUT_VERSION || !matches!(packet, Packet::GameData(_) | Packet::ChatMessage { .. }) {
+            return packet;
+        }
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        let sequenced = Packet::Sequenced {
+            epoch: self.epoch,
+            sequence,
+            packet: Box::new(packet),
+        };
+        self.unacknowledged.push_back((sequence, sequenced.clone()));
+        sequenced
+    }
+
+    pub fn acknowledge(&mut self, sequence: u32) {
+        self.unacknowledged.retain(|(unacknowledged_sequence, _)| *unacknowledged_sequence > sequence);
+    }
+
+    // In the order they were first sent, for retransmission after a reconnect.
+    pub fn unacknowledged(&self) -> impl Iterator<Item = &Packet> {
+        self.unacknowledged.iter().map(|(_, packet)| packet)
+    }
+}
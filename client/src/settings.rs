@@ -0,0 +1,164 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use clap::{Args, Parser};
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+use config::{DEFAULT_MAX_PAYLOAD_SIZE, PASSWORD_LENGTH, USERNAME_LENGTH};
+
+// Read when it exists and no other file is given.
+pub const DEFAULT_CONFIG_PATH: &str = "client.toml";
+
+#[derive(Debug, Parser)]
+#[command(about = "Interactive client for the TCP practice protocol")]
+pub struct Cli {
+    #[arg(long, env = "TCP_PRACTICE_CLIENT_CONFIG", help = "TOML settings file, defaults to client.toml when it exists")]
+    pub config: Option<PathBuf>,
+    #[arg(long, help = "Print the effective settings as TOML and exit")]
+    pub print_config: bool,
+    #[command(flatten)]
+    pub overrides: Overrides,
+}
+
+// Every setting as a flag, clap falls back to the environment variable when the flag is missing.
+#[derive(Debug, Args)]
+pub struct Overrides {
+    #[arg(long, env = "TCP_PRACTICE_SERVER_ADDRESS", help = "Address of the server")]
+    server_address: Option<SocketAddr>,
+    #[arg(long, env = "TCP_PRACTICE_USERNAME", help = "Username to log in with")]
+    username: Option<String>,
+    #[arg(long, env = "TCP_PRACTICE_PASSWORD", hide_env_values = true, help = "Password to log in with")]
+    password: Option<String>,
+    #[arg(long, env = "TCP_PRACTICE_LOG_LEVEL", help = "off, error, warn, info, debug or trace")]
+    log_level: Option<LevelFilter>,
+    #[arg(long, env = "TCP_PRACTICE_PING_INTERVAL", help = "Milliseconds between pings")]
+    ping_interval_ms: Option<u64>,
+    #[arg(long, env = "TCP_PRACTICE_RECONNECT_DELAY", help = "Milliseconds to wait between reconnect attempts")]
+    reconnect_delay_ms: Option<u64>,
+    #[arg(long, env = "TCP_PRACTICE_MAX_PAYLOAD_SIZE", help = "Largest payload in bytes accepted from the server")]
+    max_payload_size: Option<usize>,
+}
+
+impl Overrides {
+    fn apply(&self, settings: &mut Settings) {
+        if let Some(server_address) = self.server_address {
+            settings.server_address = server_address;
+        }
+        if let Some(username) = &self.username {
+            settings.username = username.clone();
+        }
+        if let Some(password) = &self.password {
+            settings.password = password.clone();
+        }
+        if let Some(log_level) = self.log_level {
+            settings.log_level = log_level;
+        }
+        if let Some(ping_interval_ms) = self.ping_interval_ms {
+            settings.ping_interval_ms = ping_interval_ms;
+        }
+        if let Some(reconnect_delay_ms) = self.reconnect_delay_ms {
+            settings.reconnect_delay_ms = reconnect_delay_ms;
+        }
+        if let Some(max_payload_size) = self.max_payload_size {
+            settings.max_payload_size = max_payload_size;
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SettingsError {
+    Read(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SettingsError::Read(path, e) => write!(f, "could not read {}: {}", path.display(), e),
+            SettingsError::Parse(path, e) => write!(f, "could not parse {}: {}", path.display(), e),
+            SettingsError::Invalid(reason) => write!(f, "invalid settings: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for SettingsError {}
+
+// Defaults, overridden by the TOML file, then by environment variables, then by flags.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub server_address: SocketAddr,
+    pub username: String,
+    // Never printed with --print-config.
+    #[serde(skip_serializing)]
+    pub password: String,
+    pub log_level: LevelFilter,
+    pub ping_interval_ms: u64,
+    pub reconnect_delay_ms: u64,
+    pub max_payload_size: usize,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            server_address: SocketAddr::from(([127, 0, 0, 1], 8080)),
+            username: "username".to_string(),
+            password: "password".to_string(),
+            log_level: LevelFilter::Info,
+            ping_interval_ms: 1000,
+            reconnect_delay_ms: 2000,
+            max_payload_size: DEFAULT_MAX_PAYLOAD_SIZE,
+        }
+    }
+}
+
+impl Settings {
+    // A file given explicitly has to exist, the default one is optional.
+    pub fn load(cli: &Cli) -> Result<Self, SettingsError> {
+        let mut settings = match &cli.config {
+            Some(path) => Settings::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => Settings::from_file(Path::new(DEFAULT_CONFIG_PATH))?,
+            None => Settings::default(),
+        };
+        cli.overrides.apply(&mut settings);
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, SettingsError> {
+        let contents = fs::read_to_string(path).map_err(|e| SettingsError::Read(path.to_path_buf(), e))?;
+        toml::from_str(&contents).map_err(|e| SettingsError::Parse(path.to_path_buf(), e))
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string_pretty(self).expect("settings always serialize")
+    }
+
+    pub fn ping_interval(&self) -> Duration {
+        Duration::from_millis(self.ping_interval_ms)
+    }
+
+    pub fn reconnect_delay(&self) -> Duration {
+        Duration::from_millis(self.reconnect_delay_ms)
+    }
+
+    fn validate(&self) -> Result<(), SettingsError> {
+        if self.username.is_empty() || self.username.len() > USERNAME_LENGTH {
+            return Err(SettingsError::Invalid(format!("username must be 1 to {} bytes", USERNAME_LENGTH)));
+        }
+        if self.password.is_empty() || self.password.len() > PASSWORD_LENGTH {
+            return Err(SettingsError::Invalid(format!("password must be 1 to {} bytes", PASSWORD_LENGTH)));
+        }
+        if self.ping_interval_ms == 0 {
+            return Err(SettingsError::Invalid("ping_interval_ms must be at least 1".to_string()));
+        }
+        if self.max_payload_size == 0 {
+            return Err(SettingsError::Invalid("max_payload_size must be at least 1".to_string()));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,490 @@
+use std::io::{self, prelude::*, ErrorKind};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant};
+use log::{debug, error, info, warn};
+use config::{DisconnectReason, EncryptionKey, FrameDecoder, FrameEncoder, Packet, AUTH_FAILED_TOKEN, READ_BUFFER_SIZE, SUPPORTED_VERSIONS};
+use crate::outbox::Outbox;
+use crate::settings::Settings;
+use crate::{auth_request, fits_version, parse_input, print_chat, Ping, INPUT_HELP};
+
+struct Client {
+    pub authenticated: bool,
+    pub token: Option<String>,
+    pub stream: TcpStream,
+    pub decoder: FrameDecoder,
+    pub encoder: FrameEncoder,
+    pub message_buffer: Vec<Vec<u8>>,
+    pub connected: bool,
+    pub retrying: bool,
+    // The server shut down, nothing reconnects after this.
+    pub stopped: bool,
+    pub ping: Ping,
+    pub outbox: Outbox,
+}
+
+impl Client {
+    fn new(stream: TcpStream, settings: &Settings) -> Self {
+        Client {
+            authenticated: false,
+            token: None,
+            stream,
+            decoder: FrameDecoder::with_max_payload_size(settings.max_payload_size).with_key(EncryptionKey::from_env()).with_resync(true),
+            encoder: FrameEncoder::new().with_key(EncryptionKey::from_env()).with_checksum(true),
+            message_buffer: Vec::new(),
+            connected: false,
+            retrying: false,
+            stopped: false,
+            ping: Ping::new(Instant::now()),
+            outbox: Outbox::new(),
+        }
+    }
+
+    fn send_message(&mut self, packet: Packet) {
+        let packet = self.outbox.wrap(packet, self.encoder.version());
+        self.queue_packet(&packet);
+    }
+
+    fn queue_packet(&mut self, packet: &Packet) {
+        match self.encoder.encode(packet) {
+            Ok(send_data) => self.message_buffer.push(send_data),
+            Err(e) => warn!("Could not encode {:?}: {}", packet.data_type(), e),
+        }
+    }
+
+    fn connection_lost(&mut self) {
+        if !self.retrying && !self.stopped {
+            warn!("Connection lost. Retrying...");
+            self.connected = false;
+            self.retrying = true;
+        }
+    }
+
+    // Anything queued for the old stream is dropped, unacknowledged packets go out again in order.
+    fn retransmit_unacknowledged(&mut self) {
+        self.message_buffer.clear();
+        let packets: Vec<Packet> = self.outbox.unacknowledged().cloned().collect();
+        for packet in &packets {
+            self.queue_packet(packet);
+        }
+    }
+}
+
+fn reading_thread(client: Arc<Mutex<Client>>) {
+    thread::spawn(move || {
+        'outer: loop {
+            {
+                let is_connected = {
+                    client.lock().unwrap().connected
+                };
+                let is_disconnected_and_not_retrying = {
+                    !is_connected && !client.lock().unwrap().retrying
+                };
+                if is_disconnected_and_not_retrying {
+                    debug!("Closing stream reading");
+                    break 'outer;
+                }
+                let is_authenticated = {
+                    let guarded_client = client.lock().unwrap();
+                    guarded_client.authenticated
+                };
+                if is_connected && is_authenticated {
+                    let mut stream = {
+                        let guarded_client = client.lock().unwrap();
+                        guarded_client.stream.try_clone().unwrap()
+                    };
+
+                    let mut buffer = [0; READ_BUFFER_SIZE];
+                    match stream.read(&mut buffer) {
+                        Ok(0) => {
+                            client.lock().unwrap().connection_lost();
+                        }
+                        Ok(bytes_read) => {
+                            let packets = {
+                                let guarded_client = &mut client.lock().unwrap();
+                                guarded_client.decoder.extend(&buffer[..bytes_read]);
+                                let mut packets = Vec::new();
+                                loop {
+                                    match guarded_client.decoder.next_packet() {
+                                        Ok(Some(packet)) => packets.push(packet),
+                                        Ok(None) => break,
+                                        Err(e) => warn!("Dropped malformed frame: {}", e),
+                                    }
+                                }
+                                packets
+                            };
+                            for packet in packets {
+                                match packet {
+                                    Packet::Ping => {
+                                        let guarded_client = &mut client.lock().unwrap();
+                                        let ping = &mut guarded_client.ping;
+                                        ping.receive();
+                                        println!("Ping: {:?}", ping.get_duration().unwrap());
+                                    }
+                                    Packet::Disconnect { reason: DisconnectReason::ServerShutdown } => {
+                                        info!("Server is shutting down.");
+                                        let guarded_client = &mut client.lock().unwrap();
+                                        guarded_client.stopped = true;
+                                        guarded_client.connected = false;
+                                        guarded_client.retrying = false;
+                                    }
+                                    Packet::Disconnect { reason: DisconnectReason::SessionExpired } => {
+                                        info!("Session expired, logging in again.");
+                                        client.lock().unwrap().token = None;
+                                    }
+                                    Packet::Disconnect { reason } => {
+                                        info!("Disconnected by the server ({:?}).", reason);
+                                    }
+                                    Packet::Ack { sequence } => {
+                                        client.lock().unwrap().outbox.acknowledge(sequence);
+                                    }
+                                    Packet::Heartbeat => {
+                                        client.lock().unwrap().queue_packet(&Packet::Heartbeat);
+                                    }
+                                    Packet::GameData(game_data) => {
+                                        println!("Game data: {}", game_data.value);
+                                    }
+                                    Packet::ChatMessage { recipient, sender, timestamp, text } => {
+                                        print_chat(recipient.as_deref(), &sender, timestamp, &text);
+                                    }
+                                    unexpected_value => {
+                                        warn!("Unexpected data type {:?}", unexpected_value.data_type());
+                                    }
+                                }
+                            }
+                        }
+                        Err(e) if e.kind() == ErrorKind::ConnectionReset => {
+                            client.lock().unwrap().connection_lost();
+                        }
+                        Err(_e) => {
+                            continue;
+                        }
+                    }
+                }
+            }
+            sleep(Duration::from_millis(1));
+        }
+    });
+}
+
+fn sending_thread(client: Arc<Mutex<Client>>) {
+    thread::spawn(move || {
+        'outer: loop {
+            {
+                let is_connected = {
+                    client.lock().unwrap().connected
+                };
+                let is_disconnected_and_not_retrying = {
+                    !is_connected && !client.lock().unwrap().retrying
+                };
+                if is_disconnected_and_not_retrying {
+                    debug!("Closing stream reading");
+                    break 'outer;
+                }
+                let is_authenticated = {
+                    client.lock().unwrap().authenticated
+                };
+                if is_connected  && is_authenticated {
+                    let message_to_send = {
+                        let mut guarded_client = client.lock().unwrap();
+
+                        if guarded_client.message_buffer.is_empty() {
+                            continue;
+                        }
+
+                        guarded_client.message_buffer.drain(..).collect::<Vec<_>>()
+                    };
+                    let mut guarded_client = client.lock().unwrap();
+                    let stream = &mut guarded_client.stream;
+
+                    let mut write_failed = false;
+                    for message in message_to_send {
+                        if let Err(e) = stream.write_all(&message) {
+                            warn!("Could not send message: {}", e);
+                            write_failed = true;
+                            break;
+                        }
+                    }
+                    let _ = stream.flush();
+                    if write_failed {
+                        guarded_client.connection_lost();
+                    }
+                }
+            }
+            sleep(Duration::from_millis(1));
+        }
+    });
+}
+
+fn ping_server(client: Arc<Mutex<Client>>, ping_interval: Duration) {
+    thread::spawn(move || {
+        'outer: loop {
+            {
+                let is_connected = {
+                    client.lock().unwrap().connected
+                };
+                let is_disconnected_and_not_retrying = {
+                    !is_connected && !client.lock().unwrap().retrying
+                };
+                if is_disconnected_and_not_retrying {
+                    debug!("Closing stream reading");
+                    break 'outer;
+                }
+                let is_authenticated = {
+                    client.lock().unwrap().authenticated
+                };
+                if is_connected && is_authenticated {
+                    let guarded_client = &mut client.lock().unwrap();
+                    guarded_client.queue_packet(&Packet::Ping);
+                    if guarded_client.ping.received_at.is_some() {
+                        guarded_client.ping = Ping::new(Instant::now());
+                    }
+                }
+            }
+            sleep(ping_interval);
+        }
+    });
+}
+
+fn input_thread(client: Arc<Mutex<Client>>) {
+    thread::spawn(move || {
+        println!("{}", INPUT_HELP);
+        for line in io::stdin().lock().lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            let mut guarded_client = client.lock().unwrap();
+            let version = guarded_client.encoder.version();
+            if let Some(packet) = parse_input(&line).filter(|packet| fits_version(packet, version)) {
+                guarded_client.send_message(packet);
+            }
+        }
+    });
+}
+
+fn wait_for_packet(client: &Arc<Mutex<Client>>, stream: &mut TcpStream, settings: &Settings) -> Option<Packet> {
+    let mut buffer = [0u8; READ_BUFFER_SIZE];
+    loop {
+        match client.lock().unwrap().decoder.next_packet() {
+            Ok(Some(packet)) => return Some(packet),
+            Ok(None) => {}
+            Err(e) => {
+                warn!("Malformed frame from server: {}", e);
+                return None;
+            }
+        }
+        match stream.read(&mut buffer) {
+            Ok(0) => {
+                warn!("Server closed the connection.");
+                return None;
+            }
+            Ok(bytes_read) => {
+                client.lock().unwrap().decoder.extend(&buffer[..bytes_read]);
+                continue;
+            }
+            Err(_e) => {
+                warn!("Could not read from server");
+            }
+        }
+        info!("Retrying in {:?}...", settings.reconnect_delay());
+        sleep(settings.reconnect_delay());
+    }
+}
+
+fn negotiate_version(client: &Arc<Mutex<Client>>, stream: &mut TcpStream, settings: &Settings) -> bool {
+    let send_data = {
+        let guarded_client = client.lock().unwrap();
+        guarded_client.encoder.encode(&Packet::Hello { versions: SUPPORTED_VERSIONS.to_vec() })
+    };
+    let send_data = match send_data {
+        Ok(send_data) => send_data,
+        Err(e) => {
+            error!("Could not encode the hello: {}", e);
+            return false;
+        }
+    };
+    if let Err(e) = stream.write_all(&send_data).and_then(|_| stream.flush()) {
+        error!("Could not send the hello: {}", e);
+        return false;
+    }
+
+    match wait_for_packet(client, stream, settings) {
+        Some(Packet::HelloAck { version, challenge }) if SUPPORTED_VERSIONS.contains(&version) => {
+            info!("Using protocol version {}", version);
+            let mut guarded_client = client.lock().unwrap();
+            guarded_client.encoder.set_version(version);
+            guarded_client.encoder.bind_to(&challenge);
+            guarded_client.decoder.bind_to(&challenge);
+            true
+        }
+        Some(Packet::HelloAck { version, .. }) => {
+            error!("Server picked unsupported protocol version {}", version);
+            false
+        }
+        Some(Packet::HelloReject { versions }) => {
+            error!("Server rejected our protocol versions {:?}, it supports {:?}", SUPPORTED_VERSIONS, versions);
+            false
+        }
+        Some(unexpected_value) => {
+            warn!("Data type unknown {:?}", unexpected_value.data_type());
+            false
+        }
+        None => false,
+    }
+}
+
+// The token is stored but the caller flips connected and authenticated, so the worker threads
+// do not touch the stream before the caller is done with it.
+fn wait_for_auth_response(client: &Arc<Mutex<Client>>, stream: &mut TcpStream, settings: &Settings) -> bool {
+    loop {
+        match wait_for_packet(client, stream, settings) {
+            Some(Packet::AuthResponse { failure: Some(failure), .. }) => {
+                error!("Authentication failed: {}", failure);
+                return false;
+            }
+            Some(Packet::AuthResponse { token, .. }) if token == AUTH_FAILED_TOKEN => {
+                error!("Authentication failed.");
+                return false;
+            }
+            Some(Packet::AuthResponse { token, .. }) => {
+                client.lock().unwrap().token = Some(token);
+                return true;
+            }
+            Some(unexpected_value) => {
+                warn!("Data type unknown {:?}", unexpected_value.data_type());
+            }
+            None => {
+                error!("Could not authenticate");
+                return false;
+            }
+        }
+    }
+}
+
+fn authenticate(client: &Arc<Mutex<Client>>, stream: &mut TcpStream, settings: &Settings) -> bool {
+    let send_data = {
+        let guarded_client = client.lock().unwrap();
+        guarded_client.encoder.encode(&auth_request(settings))
+    };
+    let send_data = match send_data {
+        Ok(send_data) => send_data,
+        Err(e) => {
+            error!("Could not encode the login: {}", e);
+            return false;
+        }
+    };
+    if let Err(e) = stream.write_all(&send_data).and_then(|_| stream.flush()) {
+        error!("Could not send the login: {}", e);
+        return false;
+    }
+
+    info!("Waiting for authentication.");
+    if !wait_for_auth_response(client, stream, settings) {
+        return false;
+    }
+    info!("Authenticated!");
+    true
+}
+
+fn resume_session(client: &Arc<Mutex<Client>>, stream: &mut TcpStream, token: String, settings: &Settings) -> bool {
+    let send_data = match client.lock().unwrap().encoder.encode(&Packet::ResumeSession { token }) {
+        Ok(send_data) => send_data,
+        Err(e) => {
+            error!("Could not encode the session token: {}", e);
+            return false;
+        }
+    };
+    if let Err(e) = stream.write_all(&send_data).and_then(|_| stream.flush()) {
+        error!("Could not send the session token: {}", e);
+        return false;
+    }
+
+    if !wait_for_auth_response(client, stream, settings) {
+        return false;
+    }
+    info!("Resumed session.");
+    true
+}
+
+// Resumes the previous session when there is one and falls back to logging in again.
+fn initialize_connection(client: Arc<Mutex<Client>>, settings: &Settings) -> bool {
+    let mut stream = {
+        let guarded_client = client.lock().unwrap();
+        guarded_client.stream.try_clone().unwrap()
+    };
+    let _ = stream.set_nonblocking(false);
+    if !negotiate_version(&client, &mut stream, settings) {
+        return false;
+    }
+    let previous_token = client.lock().unwrap().token.take();
+    if let Some(token) = previous_token {
+        if resume_session(&client, &mut stream, token, settings) {
+            return true;
+        }
+    }
+    authenticate(&client, &mut stream, settings)
+}
+
+pub fn client(settings: Settings) -> std::io::Result<()> {
+    if let Ok(new_stream) = TcpStream::connect(settings.server_address) {
+        info!("Connected to server.");
+        let client = Arc::new(Mutex::new(Client::new(new_stream, &settings)));
+
+        let connection_initialized = {
+            initialize_connection(client.clone(), &settings)
+        };
+        if connection_initialized {
+            {
+                let guarded_client = &mut client.lock().unwrap();
+                let _ = guarded_client.stream.set_nonblocking(true);
+                guarded_client.authenticated = true;
+                guarded_client.connected = true;
+            }
+            info!("Finished initialization");
+            reading_thread(client.clone());
+            ping_server(client.clone(), settings.ping_interval());
+            sending_thread(client.clone());
+            input_thread(client.clone());
+
+            let cloned_client = client.clone();
+            loop {
+                {
+                    if cloned_client.lock().unwrap().stopped {
+                        break;
+                    }
+                    if !cloned_client.lock().unwrap().connected {
+                        info!("Reconnecting...");
+                        if let Ok(new_stream) = TcpStream::connect(settings.server_address) {
+                            {
+                                let guarded_client = &mut cloned_client.lock().unwrap();
+                                guarded_client.stream = new_stream;
+                                guarded_client.decoder.clear();
+                                guarded_client.authenticated = false;
+                            }
+                            if initialize_connection(cloned_client.clone(), &settings) {
+                                let guarded_client = &mut cloned_client.lock().unwrap();
+                                let _ = guarded_client.stream.set_nonblocking(true);
+                                guarded_client.authenticated = true;
+                                guarded_client.connected = true;
+                                guarded_client.retrying = false;
+                                guarded_client.retransmit_unacknowledged();
+                                info!("Reconnected!");
+                                continue;
+                            }
+                            warn!("Could not restore the session.");
+                        }
+                        else {
+                            warn!("Failed to reconnect.");
+                        }
+                    }
+                    sleep(settings.reconnect_delay())
+                }
+                sleep(Duration::from_millis(500));
+            }
+        }
+    } else {
+        error!("Connection failed!");
+    }
+    Ok(())
+}
@@ -0,0 +1,72 @@
+use std::io;
+use bytes::BytesMut;
+use tokio_util::codec::{Decoder, Encoder};
+use crate::{FrameDecoder, FrameEncoder, Packet, PacketHeader, ProtocolError};
+
+// Protocol errors are items rather than stream errors, a Framed stream ends after its first error
+// and one bad frame should not cost the connection when the decoder can resync.
+pub type DecodedFrame = Result<(PacketHeader, Packet), ProtocolError>;
+
+// FrameDecoder and FrameEncoder wrapped for tokio_util::codec::Framed.
+#[derive(Debug)]
+pub struct PacketCodec {
+    decoder: FrameDecoder,
+    encoder: FrameEncoder,
+}
+
+impl PacketCodec {
+    pub fn new(decoder: FrameDecoder, encoder: FrameEncoder) -> Self {
+        PacketCodec {
+            decoder,
+            encoder,
+        }
+    }
+
+    pub fn decoder(&self) -> &FrameDecoder {
+        &self.decoder
+    }
+
+    pub fn encoder(&self) -> &FrameEncoder {
+        &self.encoder
+    }
+
+    pub fn encoder_mut(&mut self) -> &mut FrameEncoder {
+        &mut self.encoder
+    }
+
+    // Both directions use the challenge from HelloAck.
+    pub fn bind_to(&mut self, binding: &[u8]) {
+        self.decoder.bind_to(binding);
+        self.encoder.bind_to(binding);
+    }
+}
+
+impl Default for PacketCodec {
+    fn default() -> Self {
+        PacketCodec::new(FrameDecoder::new(), FrameEncoder::new())
+    }
+}
+
+impl Decoder for PacketCodec {
+    type Item = DecodedFrame;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if !src.is_empty() {
+            self.decoder.extend(src);
+            src.clear();
+        }
+        Ok(self.decoder.next_packet_with_header().transpose())
+    }
+}
+
+// A packet that cannot be encoded fails with InvalidData, writers can drop it and keep the connection.
+impl Encoder<Packet> for PacketCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, packet: Packet, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let bytes = self.encoder.encode(&packet).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        dst.extend_from_slice(&bytes);
+        Ok(())
+    }
+}
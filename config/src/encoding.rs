@@ -0,0 +1,151 @@
+use std::io::{Read, Write};
+use chacha20poly1305::aead::{Aead, AeadCore, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, KeyInit, Nonce};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use crate::ProtocolError;
+
+pub const ENCRYPTION_KEY_SIZE: usize = 32;
+pub const NONCE_SIZE: usize = 12;
+// Random bytes the server hands each connection in HelloAck.
+pub const CHALLENGE_SIZE: usize = 16;
+pub const COMPRESSION_THRESHOLD: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadEncoding {
+    Raw,
+    Compressed,
+    Encrypted,
+}
+
+impl PayloadEncoding {
+    pub fn from_u8(value: u8) -> Option<PayloadEncoding> {
+        match value {
+            0 => Some(PayloadEncoding::Raw),
+            1 => Some(PayloadEncoding::Compressed),
+            2 => Some(PayloadEncoding::Encrypted),
+            _ => None,
+        }
+    }
+    pub fn to_u8(&self) -> u8 {
+        match self {
+            PayloadEncoding::Raw => 0,
+            PayloadEncoding::Compressed => 1,
+            PayloadEncoding::Encrypted => 2,
+        }
+    }
+}
+
+// The binding goes into the associated data next to the header. Bound to a connection's challenge,
+// a captured login only decrypts on the connection it was sent on and cannot be replayed on another.
+#[derive(Clone, PartialEq, Eq)]
+pub struct EncryptionKey {
+    bytes: [u8; ENCRYPTION_KEY_SIZE],
+    binding: Vec<u8>,
+}
+
+impl EncryptionKey {
+    pub fn new(bytes: [u8; ENCRYPTION_KEY_SIZE]) -> Self {
+        EncryptionKey {
+            bytes,
+            binding: Vec::new(),
+        }
+    }
+
+    pub fn bound_to(&self, binding: &[u8]) -> Self {
+        EncryptionKey {
+            bytes: self.bytes,
+            binding: binding.to_vec(),
+        }
+    }
+
+    fn associated_data(&self, header: &[u8]) -> Vec<u8> {
+        let mut associated_data = header.to_vec();
+        associated_data.extend_from_slice(&self.binding);
+        associated_data
+    }
+
+    pub fn from_hex(value: &str) -> Option<Self> {
+        let value = value.trim();
+        if value.len() != ENCRYPTION_KEY_SIZE * 2 || !value.is_ascii() {
+            return None;
+        }
+        let mut bytes = [0u8; ENCRYPTION_KEY_SIZE];
+        for (index, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&value[index * 2..index * 2 + 2], 16).ok()?;
+        }
+        Some(EncryptionKey::new(bytes))
+    }
+
+    // Reads a hex encoded key shared by server and client, unset or malformed means no encryption.
+    pub fn from_env() -> Option<Self> {
+        std::env::var(crate::ENCRYPTION_KEY_ENV).ok().and_then(|value| EncryptionKey::from_hex(&value))
+    }
+}
+
+// Keeps the key out of logs.
+impl std::fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+// The header bytes are passed as associated data so an encrypted payload cannot be replayed under another type,
+// the key's binding so it cannot be replayed on another connection.
+pub fn apply_encoding(encoding: PayloadEncoding, payload: &[u8], header: &[u8], key: Option<&EncryptionKey>) -> Result<Vec<u8>, ProtocolError> {
+    match encoding {
+        PayloadEncoding::Raw => Ok(payload.to_vec()),
+        PayloadEncoding::Compressed => {
+            let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(payload).map_err(|_| ProtocolError::CompressionFailed)?;
+            encoder.finish().map_err(|_| ProtocolError::CompressionFailed)
+        }
+        PayloadEncoding::Encrypted => {
+            let key = key.ok_or(ProtocolError::MissingKey)?;
+            let cipher = ChaCha20Poly1305::new(Key::from_slice(&key.bytes));
+            let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+            let ciphertext = cipher
+                .encrypt(&nonce, Payload { msg: payload, aad: &key.associated_data(header) })
+                .map_err(|_| ProtocolError::EncryptionFailed)?;
+            let mut encrypted = nonce.to_vec();
+            encrypted.extend_from_slice(&ciphertext);
+            Ok(encrypted)
+        }
+    }
+}
+
+pub fn remove_encoding(encoding: PayloadEncoding, payload: &[u8], header: &[u8], key: Option<&EncryptionKey>, max_payload_size: usize) -> Result<Vec<u8>, ProtocolError> {
+    match encoding {
+        PayloadEncoding::Raw => Ok(payload.to_vec()),
+        PayloadEncoding::Compressed => {
+            // Read one byte past the limit so an oversized payload is noticed without inflating all of it.
+            let mut decompressed = Vec::new();
+            DeflateDecoder::new(payload)
+                .take(max_payload_size as u64 + 1)
+                .read_to_end(&mut decompressed)
+                .map_err(|_| ProtocolError::DecompressionFailed)?;
+            if decompressed.len() > max_payload_size {
+                return Err(ProtocolError::OversizePayload {
+                    size: decompressed.len(),
+                    max: max_payload_size,
+                });
+            }
+            Ok(decompressed)
+        }
+        PayloadEncoding::Encrypted => {
+            let key = key.ok_or(ProtocolError::MissingKey)?;
+            if payload.len() < NONCE_SIZE {
+                return Err(ProtocolError::Truncated {
+                    expected: NONCE_SIZE,
+                    actual: payload.len(),
+                });
+            }
+            let (nonce, ciphertext) = payload.split_at(NONCE_SIZE);
+            let cipher = ChaCha20Poly1305::new(Key::from_slice(&key.bytes));
+            cipher
+                .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: &key.associated_data(header) })
+                .map_err(|_| ProtocolError::DecryptionFailed)
+        }
+    }
+}
@@ -0,0 +1,44 @@
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolError {
+    BadVersion(u8),
+    UnknownType(u8),
+    UnknownEncoding(u8),
+    InvalidUtf8,
+    EmbeddedNul,
+    Truncated { expected: usize, actual: usize },
+    OversizePayload { size: usize, max: usize },
+    ChecksumMismatch { expected: u32, actual: u32 },
+    NoFixedLayout(u8),
+    NestedSequence,
+    MissingKey,
+    CompressionFailed,
+    DecompressionFailed,
+    EncryptionFailed,
+    DecryptionFailed,
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::BadVersion(version) => write!(f, "unsupported packet version {}", version),
+            ProtocolError::UnknownType(data_type) => write!(f, "unknown data type {}", data_type),
+            ProtocolError::UnknownEncoding(encoding) => write!(f, "unknown payload encoding {}", encoding),
+            ProtocolError::InvalidUtf8 => write!(f, "string field is not valid UTF-8"),
+            ProtocolError::EmbeddedNul => write!(f, "string field contains a NUL byte"),
+            ProtocolError::Truncated { expected, actual } => write!(f, "truncated frame, expected {} bytes but got {}", expected, actual),
+            ProtocolError::OversizePayload { size, max } => write!(f, "payload of {} bytes exceeds the maximum of {}", size, max),
+            ProtocolError::ChecksumMismatch { expected, actual } => write!(f, "checksum mismatch, frame says {:08x} but data hashes to {:08x}", expected, actual),
+            ProtocolError::NoFixedLayout(data_type) => write!(f, "data type {} has no fixed size layout", data_type),
+            ProtocolError::NestedSequence => write!(f, "sequenced packet wraps another sequenced packet"),
+            ProtocolError::MissingKey => write!(f, "encrypted payload but no encryption key is configured"),
+            ProtocolError::CompressionFailed => write!(f, "could not compress payload"),
+            ProtocolError::DecompressionFailed => write!(f, "could not decompress payload"),
+            ProtocolError::EncryptionFailed => write!(f, "could not encrypt payload"),
+            ProtocolError::DecryptionFailed => write!(f, "could not decrypt payload"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
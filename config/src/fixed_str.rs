@@ -0,0 +1,97 @@
+use crate::ProtocolError;
+
+// A string stored in exactly N bytes for the fixed size layouts.
+// The text is right aligned and padded on the left with NUL bytes, decoding strips that padding again.
+// Values longer than N bytes are cut at the last character boundary that fits, NUL inside the text is rejected
+// because it could not be told apart from padding.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FixedStr<const N: usize> {
+    value: String,
+}
+
+impl<const N: usize> FixedStr<N> {
+    pub fn new(value: &str) -> Result<Self, ProtocolError> {
+        if value.contains('\0') {
+            return Err(ProtocolError::EmbeddedNul);
+        }
+        let mut length = value.len().min(N);
+        while !value.is_char_boundary(length) {
+            length -= 1;
+        }
+        Ok(FixedStr {
+            value: value[..length].to_string(),
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.value
+    }
+
+    pub fn into_string(self) -> String {
+        self.value
+    }
+
+    pub fn to_bytes(&self) -> [u8; N] {
+        let mut field = [0u8; N];
+        let value_bytes = self.value.as_bytes();
+        field[N - value_bytes.len()..].copy_from_slice(value_bytes);
+        field
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        if bytes.len() != N {
+            return Err(ProtocolError::Truncated {
+                expected: N,
+                actual: bytes.len(),
+            });
+        }
+        let start_index = bytes.iter().position(|byte| *byte != 0).unwrap_or(N);
+        let value_bytes = &bytes[start_index..];
+        if value_bytes.contains(&0) {
+            return Err(ProtocolError::EmbeddedNul);
+        }
+        let value = std::str::from_utf8(value_bytes).map_err(|_| ProtocolError::InvalidUtf8)?;
+        Ok(FixedStr {
+            value: value.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_values_are_cut_on_a_character_boundary() {
+        // "é" takes two bytes, the second would end up past the fifth byte.
+        let name = FixedStr::<5>::new("abcdé").unwrap();
+        assert_eq!(name.as_str(), "abcd");
+        assert_eq!(name.to_bytes(), [0, b'a', b'b', b'c', b'd']);
+        let name = FixedStr::<4>::new("€€").unwrap();
+        assert_eq!(name.as_str(), "€");
+        assert_eq!(FixedStr::<2>::new("€").unwrap().as_str(), "");
+    }
+
+    #[test]
+    fn embedded_nul_is_rejected() {
+        assert_eq!(FixedStr::<8>::new("al\0ce"), Err(ProtocolError::EmbeddedNul));
+        assert_eq!(FixedStr::<4>::from_bytes(&[0, b'a', 0, b'b']), Err(ProtocolError::EmbeddedNul));
+    }
+
+    #[test]
+    fn padding_is_stripped_when_decoding() {
+        for value in ["", "bob", "zoë", "12345678"] {
+            let name = FixedStr::<8>::new(value).unwrap();
+            let bytes = name.to_bytes();
+            assert_eq!(&bytes[8 - value.len()..], value.as_bytes());
+            assert!(bytes[..8 - value.len()].iter().all(|byte| *byte == 0));
+            assert_eq!(FixedStr::<8>::from_bytes(&bytes).unwrap(), name);
+        }
+    }
+
+    #[test]
+    fn wrong_length_and_bad_utf8_are_rejected() {
+        assert_eq!(FixedStr::<4>::from_bytes(&[b'a'; 3]), Err(ProtocolError::Truncated { expected: 4, actual: 3 }));
+        assert_eq!(FixedStr::<4>::from_bytes(&[0, 0, 0xC3, 0x28]), Err(ProtocolError::InvalidUtf8));
+    }
+}
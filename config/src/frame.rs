@@ -0,0 +1,463 @@
+use crate::{append_checksum, read_length_field, DataType, Encode, EncryptionKey, Packet, PacketHeader, PayloadEncoding, ProtocolError, COMPRESSION_THRESHOLD, CHECKSUM_SIZE, DEFAULT_MAX_PAYLOAD_SIZE, FIXED_LAYOUT_VERSION, LENGTH_PREFIXED_VERSION, PACKET_INFO_SIZE};
+
+// Accumulates bytes read from a stream and hands out complete packets.
+// Reads may end anywhere inside a frame or contain several frames, leftover bytes are kept for the next call.
+#[derive(Debug)]
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+    max_payload_size: usize,
+    key: Option<EncryptionKey>,
+    resync: bool,
+    checksum_failures: u64,
+    discarded_bytes: u64,
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        FrameDecoder::new()
+    }
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        FrameDecoder::with_max_payload_size(DEFAULT_MAX_PAYLOAD_SIZE)
+    }
+
+    pub fn with_max_payload_size(max_payload_size: usize) -> Self {
+        FrameDecoder {
+            buffer: Vec::new(),
+            max_payload_size,
+            key: None,
+            resync: false,
+            checksum_failures: 0,
+            discarded_bytes: 0,
+        }
+    }
+
+    // With resync enabled a frame failing its checksum only costs its first byte, the decoder then
+    // searches forward for the next believable frame instead of trusting the corrupted length.
+    pub fn with_resync(mut self, resync: bool) -> Self {
+        self.resync = resync;
+        self
+    }
+
+    pub fn checksum_failures(&self) -> u64 {
+        self.checksum_failures
+    }
+
+    pub fn discarded_bytes(&self) -> u64 {
+        self.discarded_bytes
+    }
+
+    pub fn with_key(mut self, key: Option<EncryptionKey>) -> Self {
+        self.key = key;
+        self
+    }
+
+    // Encrypted frames from here on only decrypt when sent with the same binding.
+    pub fn bind_to(&mut self, binding: &[u8]) {
+        self.key = self.key.as_ref().map(|key| key.bound_to(binding));
+    }
+
+    pub fn max_payload_size(&self) -> usize {
+        self.max_payload_size
+    }
+
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    pub fn buffered_len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+    }
+
+    pub fn next_packet(&mut self) -> Result<Option<Packet>, ProtocolError> {
+        self.next_packet_with_header().map(|frame| frame.map(|(_header, packet)| packet))
+    }
+
+    // A frame that fails to decode is consumed and reported, decoding continues with the next one.
+    // When the frame size itself cannot be trusted the decoder skips ahead to the next believable frame.
+    pub fn next_packet_with_header(&mut self) -> Result<Option<(PacketHeader, Packet)>, ProtocolError> {
+        let frame_size = match frame_size(&self.buffer, self.max_payload_size) {
+            Ok(Some(frame_size)) => frame_size,
+            Ok(None) => return Ok(None),
+            Err(e) => {
+                self.resync();
+                return Err(e);
+            }
+        };
+        match Packet::decode_with(&self.buffer[..frame_size], self.key.as_ref(), self.max_payload_size) {
+            Ok(packet) => {
+                let header = PacketHeader::from_bytes([self.buffer[0], self.buffer[1]]);
+                self.buffer.drain(..frame_size);
+                Ok(Some((header, packet)))
+            }
+            Err(e) => {
+                if let ProtocolError::ChecksumMismatch { .. } = e {
+                    self.checksum_failures += 1;
+                    if self.resync {
+                        self.resync();
+                        return Err(e);
+                    }
+                }
+                self.discard(frame_size);
+                Err(e)
+            }
+        }
+    }
+
+    fn discard(&mut self, length: usize) {
+        self.buffer.drain(..length);
+        self.discarded_bytes += length as u64;
+    }
+
+    fn resync(&mut self) {
+        let mut offset = 1;
+        while offset < self.buffer.len() {
+            let candidate = &self.buffer[offset..];
+            if plausible_header(candidate) {
+                match frame_size(candidate, self.max_payload_size) {
+                    Ok(None) => break,
+                    Ok(Some(frame_size)) => {
+                        if Packet::decode_with(&candidate[..frame_size], self.key.as_ref(), self.max_payload_size).is_ok() {
+                            break;
+                        }
+                    }
+                    Err(_e) => {}
+                }
+            }
+            offset += 1;
+        }
+        self.discard(offset.min(self.buffer.len()));
+    }
+}
+
+// Ok(None) while the frame is still incomplete.
+fn frame_size(bytes: &[u8], max_payload_size: usize) -> Result<Option<usize>, ProtocolError> {
+    if bytes.len() < PACKET_INFO_SIZE {
+        return Ok(None);
+    }
+    let header = PacketHeader::from_bytes([bytes[0], bytes[1]]);
+    let info_size = header.info_size()?;
+    if bytes.len() < info_size {
+        return Ok(None);
+    }
+    let frame_size = if header.version == LENGTH_PREFIXED_VERSION {
+        let (payload_size, has_checksum) = read_length_field(bytes)?;
+        if payload_size > max_payload_size {
+            return Err(ProtocolError::OversizePayload {
+                size: payload_size,
+                max: max_payload_size,
+            });
+        }
+        if has_checksum {
+            info_size + payload_size + CHECKSUM_SIZE
+        } else {
+            info_size + payload_size
+        }
+    } else {
+        info_size + header.fixed_payload_size().ok_or(ProtocolError::UnknownType(bytes[1] & 0x3F))?
+    };
+    if bytes.len() < frame_size {
+        return Ok(None);
+    }
+    Ok(Some(frame_size))
+}
+
+// Used while resyncing, a length prefixed frame only counts when it carries a checksum we can verify.
+fn plausible_header(bytes: &[u8]) -> bool {
+    if bytes.len() < PACKET_INFO_SIZE {
+        return true;
+    }
+    let header = PacketHeader::from_bytes([bytes[0], bytes[1]]);
+    if header.data_type == DataType::Unknown || PayloadEncoding::from_u8(header.encoding).is_none() {
+        return false;
+    }
+    match header.version {
+        LENGTH_PREFIXED_VERSION => bytes.len() <= PACKET_INFO_SIZE || bytes[PACKET_INFO_SIZE] & 0x80 != 0,
+        FIXED_LAYOUT_VERSION => header.fixed_payload_size().is_some(),
+        _ => false,
+    }
+}
+
+// Picks the payload encoding for outgoing packets: sensitive packets are encrypted when a key is set
+// and payloads above the compression threshold are compressed.
+// Peers that negotiated the fixed layout version only get the version 1 layouts.
+#[derive(Debug, Clone)]
+pub struct FrameEncoder {
+    key: Option<EncryptionKey>,
+    compression_threshold: usize,
+    version: u8,
+    checksum: bool,
+}
+
+impl Default for FrameEncoder {
+    fn default() -> Self {
+        FrameEncoder::new()
+    }
+}
+
+impl FrameEncoder {
+    pub fn new() -> Self {
+        FrameEncoder {
+            key: None,
+            compression_threshold: COMPRESSION_THRESHOLD,
+            version: LENGTH_PREFIXED_VERSION,
+            checksum: false,
+        }
+    }
+
+    pub fn with_checksum(mut self, checksum: bool) -> Self {
+        self.checksum = checksum;
+        self
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    pub fn set_version(&mut self, version: u8) {
+        self.version = version;
+    }
+
+    pub fn with_key(mut self, key: Option<EncryptionKey>) -> Self {
+        self.key = key;
+        self
+    }
+
+    pub fn bind_to(&mut self, binding: &[u8]) {
+        self.key = self.key.as_ref().map(|key| key.bound_to(binding));
+    }
+
+    pub fn with_compression_threshold(mut self, compression_threshold: usize) -> Self {
+        self.compression_threshold = compression_threshold;
+        self
+    }
+
+    pub fn encoding_for(&self, packet: &Packet) -> PayloadEncoding {
+        if self.key.is_some() && packet.is_sensitive() {
+            PayloadEncoding::Encrypted
+        } else if packet.encode_payload().len() >= self.compression_threshold {
+            PayloadEncoding::Compressed
+        } else {
+            PayloadEncoding::Raw
+        }
+    }
+
+    // Sensitive packets never fall back to a raw frame, failing to encrypt one is an error.
+    // With a key they skip the fixed layout too, it has no room for an encrypted payload.
+    pub fn encode(&self, packet: &Packet) -> Result<Vec<u8>, ProtocolError> {
+        if self.version == FIXED_LAYOUT_VERSION && !(self.key.is_some() && packet.is_sensitive()) {
+            if let Ok(bytes) = packet.encode_fixed() {
+                return Ok(bytes);
+            }
+        }
+        let bytes = match packet.encode_as(self.encoding_for(packet), self.key.as_ref()) {
+            Ok(bytes) => bytes,
+            Err(e) if packet.is_sensitive() => return Err(e),
+            // Compression is only an optimisation, the raw frame carries the same packet.
+            Err(_e) => packet.encode(),
+        };
+        if self.checksum {
+            Ok(append_checksum(bytes))
+        } else {
+            Ok(bytes)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{GameData, LENGTH_PREFIXED_INFO_SIZE};
+
+    fn v2_frames() -> (Vec<Packet>, Vec<u8>) {
+        let encoder = FrameEncoder::new().with_checksum(true);
+        let packets = vec![
+            Packet::Hello { versions: vec![1, 2] },
+            Packet::AuthRequest { username: "alice".to_string(), password: "hunter2".to_string() },
+            Packet::GameData(GameData { value: 7, extra: vec![1, 2, 3] }),
+            Packet::Ping,
+        ];
+        let bytes = packets.iter().flat_map(|packet| encoder.encode(packet).unwrap()).collect();
+        (packets, bytes)
+    }
+
+    fn drain(decoder: &mut FrameDecoder) -> Vec<Result<Packet, ProtocolError>> {
+        let mut results = Vec::new();
+        loop {
+            match decoder.next_packet() {
+                Ok(Some(packet)) => results.push(Ok(packet)),
+                Ok(None) => return results,
+                Err(e) => results.push(Err(e)),
+            }
+        }
+    }
+
+    fn decoded(decoder: &mut FrameDecoder) -> Vec<Packet> {
+        drain(decoder).into_iter().collect::<Result<_, _>>().unwrap()
+    }
+
+    #[test]
+    fn split_at_every_boundary() {
+        let (packets, bytes) = v2_frames();
+        for split in 0..=bytes.len() {
+            let mut decoder = FrameDecoder::new();
+            decoder.extend(&bytes[..split]);
+            let mut received = decoded(&mut decoder);
+            decoder.extend(&bytes[split..]);
+            received.extend(decoded(&mut decoder));
+            assert_eq!(received, packets, "split at {}", split);
+            assert_eq!(decoder.buffered_len(), 0);
+        }
+    }
+
+    #[test]
+    fn one_byte_at_a_time() {
+        let (packets, bytes) = v2_frames();
+        let mut decoder = FrameDecoder::new();
+        let mut received = Vec::new();
+        for byte in bytes {
+            decoder.extend(&[byte]);
+            received.extend(decoded(&mut decoder));
+        }
+        assert_eq!(received, packets);
+    }
+
+    #[test]
+    fn several_frames_in_one_read() {
+        let (packets, bytes) = v2_frames();
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&bytes);
+        assert_eq!(decoded(&mut decoder), packets);
+    }
+
+    #[test]
+    fn fixed_and_length_prefixed_frames_mixed() {
+        let encoder = FrameEncoder::new().with_checksum(true);
+        let packets = vec![
+            Packet::GameData(GameData::new(1)),
+            Packet::Ack { sequence: 3 },
+            Packet::Ping,
+            Packet::Heartbeat,
+            Packet::GameData(GameData::new(2)),
+        ];
+        let mut bytes = Vec::new();
+        for packet in &packets {
+            match packet.encode_fixed() {
+                Ok(fixed) => bytes.extend(fixed),
+                Err(_) => bytes.extend(encoder.encode(packet).unwrap()),
+            }
+        }
+        for split in 0..=bytes.len() {
+            let mut decoder = FrameDecoder::new();
+            decoder.extend(&bytes[..split]);
+            let mut received = decoded(&mut decoder);
+            decoder.extend(&bytes[split..]);
+            received.extend(decoded(&mut decoder));
+            assert_eq!(received, packets, "split at {}", split);
+        }
+    }
+
+    #[test]
+    fn oversize_frame_is_refused() {
+        let encoder = FrameEncoder::new().with_checksum(true);
+        let mut bytes = encoder.encode(&Packet::GameData(GameData { value: 1, extra: vec![0; 100] })).unwrap();
+        bytes.extend(encoder.encode(&Packet::Ping).unwrap());
+        let mut decoder = FrameDecoder::with_max_payload_size(16);
+        decoder.extend(&bytes);
+        let results = drain(&mut decoder);
+        assert!(matches!(results[0], Err(ProtocolError::OversizePayload { max: 16, .. })));
+        assert_eq!(results.last(), Some(&Ok(Packet::Ping)));
+    }
+
+    #[test]
+    fn bad_version_keeps_the_frames_after_it() {
+        let (packets, frames) = v2_frames();
+        let mut bytes = vec![9, DataType::Ping.to_u8(), 0, 0];
+        bytes.extend(&frames);
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&bytes);
+        let results = drain(&mut decoder);
+        assert_eq!(results[0], Err(ProtocolError::BadVersion(9)));
+        let received: Vec<Packet> = results.into_iter().filter_map(Result::ok).collect();
+        assert_eq!(received, packets);
+    }
+
+    #[test]
+    fn sensitive_packets_stay_encrypted_on_the_fixed_layout() {
+        let key = EncryptionKey::new([7; crate::ENCRYPTION_KEY_SIZE]);
+        let mut encoder = FrameEncoder::new().with_key(Some(key.clone()));
+        encoder.set_version(FIXED_LAYOUT_VERSION);
+        let login = Packet::AuthRequest { username: "alice".to_string(), password: "hunter2".to_string() };
+        let bytes = encoder.encode(&login).unwrap();
+        assert!(!bytes.windows(7).any(|window| window == b"hunter2"));
+        let mut decoder = FrameDecoder::new().with_key(Some(key));
+        decoder.extend(&bytes);
+        let (header, packet) = decoder.next_packet_with_header().unwrap().unwrap();
+        assert_eq!(header.encoding, PayloadEncoding::Encrypted.to_u8());
+        assert_eq!(packet, login);
+        // Packets without secrets still use the fixed layout.
+        assert_eq!(encoder.encode(&Packet::Ping).unwrap(), Packet::Ping.encode_fixed().unwrap());
+    }
+
+    #[test]
+    fn replayed_login_is_refused_on_another_connection() {
+        let key = EncryptionKey::new([7; crate::ENCRYPTION_KEY_SIZE]);
+        let mut encoder = FrameEncoder::new().with_key(Some(key.clone()));
+        encoder.bind_to(&[1; crate::CHALLENGE_SIZE]);
+        let login = Packet::AuthRequest { username: "alice".to_string(), password: "hunter2".to_string() };
+        let bytes = encoder.encode(&login).unwrap();
+
+        let mut original = FrameDecoder::new().with_key(Some(key.clone()));
+        original.bind_to(&[1; crate::CHALLENGE_SIZE]);
+        original.extend(&bytes);
+        assert_eq!(decoded(&mut original), vec![login]);
+
+        // Captured and sent again on a connection that was handed another challenge.
+        let mut replayed = FrameDecoder::new().with_key(Some(key.clone()));
+        replayed.bind_to(&[2; crate::CHALLENGE_SIZE]);
+        replayed.extend(&bytes);
+        assert_eq!(drain(&mut replayed), vec![Err(ProtocolError::DecryptionFailed)]);
+
+        let mut unbound = FrameDecoder::new().with_key(Some(key));
+        unbound.extend(&bytes);
+        assert_eq!(drain(&mut unbound), vec![Err(ProtocolError::DecryptionFailed)]);
+    }
+
+    fn corrupted_frames() -> (Vec<Packet>, Vec<u8>) {
+        let (packets, mut bytes) = v2_frames();
+        // Inside the payload of the first frame, after header and length.
+        bytes[LENGTH_PREFIXED_INFO_SIZE + 2] ^= 0xFF;
+        (packets, bytes)
+    }
+
+    #[test]
+    fn checksum_failure_without_resync() {
+        let (packets, bytes) = corrupted_frames();
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&bytes);
+        let results = drain(&mut decoder);
+        assert!(matches!(results[0], Err(ProtocolError::ChecksumMismatch { .. })));
+        let received: Vec<Packet> = results.into_iter().filter_map(Result::ok).collect();
+        assert_eq!(received, packets[1..]);
+        assert_eq!(decoder.checksum_failures(), 1);
+    }
+
+    #[test]
+    fn checksum_failure_with_resync() {
+        let (packets, bytes) = corrupted_frames();
+        let mut decoder = FrameDecoder::new().with_resync(true);
+        decoder.extend(&bytes);
+        let results = drain(&mut decoder);
+        assert!(matches!(results[0], Err(ProtocolError::ChecksumMismatch { .. })));
+        let received: Vec<Packet> = results.into_iter().filter_map(Result::ok).collect();
+        assert_eq!(received, packets[1..]);
+        assert_eq!(decoder.checksum_failures(), 1);
+        assert!(decoder.discarded_bytes() > 0);
+    }
+}
@@ -1,5 +1,39 @@
+#[cfg(feature = "codec")]
+mod codec;
+mod encoding;
+mod error;
+mod fixed_str;
+mod frame;
+mod payload;
+
+use std::fmt;
+#[cfg(feature = "codec")]
+pub use codec::{DecodedFrame, PacketCodec};
+pub use encoding::{EncryptionKey, PayloadEncoding, CHALLENGE_SIZE, COMPRESSION_THRESHOLD, ENCRYPTION_KEY_SIZE, NONCE_SIZE};
+pub use error::ProtocolError;
+pub use fixed_str::FixedStr;
+pub use frame::{FrameDecoder, FrameEncoder};
+pub use payload::{PayloadReader, PayloadWriter};
+
 pub const PACKET_INFO_SIZE: usize = 2;
 
+pub const READ_BUFFER_SIZE: usize = 1024;
+
+pub const FIXED_LAYOUT_VERSION: u8 = 1;
+pub const LENGTH_PREFIXED_VERSION: u8 = 2;
+pub const PAYLOAD_LENGTH_SIZE: usize = 4;
+pub const LENGTH_PREFIXED_INFO_SIZE: usize = PACKET_INFO_SIZE + PAYLOAD_LENGTH_SIZE;
+pub const DEFAULT_MAX_PAYLOAD_SIZE: usize = 64 * 1024;
+
+// The top bit of the payload length marks a CRC32 trailer after the payload.
+pub const CHECKSUM_FLAG: u32 = 1 << 31;
+pub const CHECKSUM_SIZE: usize = 4;
+
+// Highest version last, negotiation picks the highest version both sides list.
+pub const SUPPORTED_VERSIONS: &[u8] = &[FIXED_LAYOUT_VERSION, LENGTH_PREFIXED_VERSION];
+
+pub const ENCRYPTION_KEY_ENV: &str = "TCP_PRACTICE_KEY";
+
 pub const PING_PACKET_SIZE: usize = 1;
 
 pub const GAME_PACKET_VERSION: u8 = 1;
@@ -7,6 +41,8 @@ pub const GAME_PACKET_SIZE: usize = 2;
 
 pub const AUTH_RESPONSE_VERSION: u8 = 1;
 pub const AUTH_RESPONSE_SIZE: usize = 32;
+// Sent in place of a session token when authentication fails.
+pub const AUTH_FAILED_TOKEN: &str = "0";
 
 pub const AUTH_REQUEST_VERSION: u8 = 1;
 pub const AUTH_REQUEST_SIZE: usize = 52;
@@ -14,12 +50,28 @@ pub const AUTH_REQUEST_SIZE: usize = 52;
 pub const USERNAME_LENGTH: usize = 20;
 pub const PASSWORD_LENGTH: usize = 32;
 
-#[derive(Debug)]
+pub type Username = FixedStr<USERNAME_LENGTH>;
+pub type Password = FixedStr<PASSWORD_LENGTH>;
+pub type AuthToken = FixedStr<AUTH_RESPONSE_SIZE>;
+
+// Longest chat text in bytes, the server refuses anything longer.
+pub const MAX_CHAT_MESSAGE_LENGTH: usize = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum DataType {
     AuthRequest,
     AuthResponse,
     Ping,
     Disconnect,
+    GameData,
+    Hello,
+    HelloAck,
+    HelloReject,
+    Sequenced,
+    Ack,
+    ResumeSession,
+    Heartbeat,
+    ChatMessage,
     Unknown,
 }
 
@@ -30,6 +82,15 @@ impl DataType {
             2 => DataType::AuthResponse,
             3 => DataType::Ping,
             4 => DataType::Disconnect,
+            5 => DataType::GameData,
+            6 => DataType::Hello,
+            7 => DataType::HelloAck,
+            8 => DataType::HelloReject,
+            9 => DataType::Sequenced,
+            10 => DataType::Ack,
+            11 => DataType::ResumeSession,
+            12 => DataType::Heartbeat,
+            13 => DataType::ChatMessage,
             _ => DataType::Unknown,
         }
     }
@@ -39,113 +100,543 @@ impl DataType {
             DataType::AuthResponse => 2,
             DataType::Ping => 3,
             DataType::Disconnect => 4,
-            DataType::Unknown => 5,
+            DataType::GameData => 5,
+            DataType::Hello => 6,
+            DataType::HelloAck => 7,
+            DataType::HelloReject => 8,
+            DataType::Sequenced => 9,
+            DataType::Ack => 10,
+            DataType::ResumeSession => 11,
+            DataType::Heartbeat => 12,
+            DataType::ChatMessage => 13,
+            DataType::Unknown => 0,
         }
     }
 }
 
-pub fn get_package_type(bytes: [u8; 2]) -> (u8, u8, DataType) {
-    let version: u8 = bytes[0];
-    let encoding_and_type: u8 = bytes[1];
-    let encoding: u8 = encoding_and_type >> 6;
-    let data_type: DataType = DataType::from_u8(encoding_and_type & 0x3F);
+// Why a peer is being disconnected. The fixed layout has no room for it and always reads as Unspecified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    Unspecified,
+    // The client logged out.
+    Logout,
+    // The server is going away, reconnecting will not help.
+    ServerShutdown,
+    // Nothing arrived from the client for too long.
+    IdleTimeout,
+    // The client kept sending faster than the server allows.
+    RateLimited,
+    // The client did not read fast enough and too much was waiting to be sent to it.
+    SlowConsumer,
+    // The session ran out while connected, the client has to log in again.
+    SessionExpired,
+    // A code from a newer peer.
+    Unknown(u8),
+}
+
+impl DisconnectReason {
+    pub fn from_u8(value: u8) -> DisconnectReason {
+        match value {
+            0 => DisconnectReason::Unspecified,
+            1 => DisconnectReason::Logout,
+            2 => DisconnectReason::ServerShutdown,
+            3 => DisconnectReason::IdleTimeout,
+            4 => DisconnectReason::RateLimited,
+            5 => DisconnectReason::SlowConsumer,
+            6 => DisconnectReason::SessionExpired,
+            value => DisconnectReason::Unknown(value),
+        }
+    }
+
+    pub fn to_u8(&self) -> u8 {
+        match self {
+            DisconnectReason::Unspecified => 0,
+            DisconnectReason::Logout => 1,
+            DisconnectReason::ServerShutdown => 2,
+            DisconnectReason::IdleTimeout => 3,
+            DisconnectReason::RateLimited => 4,
+            DisconnectReason::SlowConsumer => 5,
+            DisconnectReason::SessionExpired => 6,
+            DisconnectReason::Unknown(value) => *value,
+        }
+    }
+}
 
-    (version, encoding, data_type)
+// Why a login or resume was refused. The fixed layout and builds from before the reason code carry none.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthFailure {
+    // Unknown username or wrong password.
+    InvalidCredentials,
+    // The session token is unknown or has expired.
+    InvalidSession,
+    // Too many failed attempts, every login is refused until the lockout is over.
+    LockedOut { retry_after_secs: u32 },
+    // A code from a newer peer.
+    Unknown(u8),
 }
 
-pub fn unpack_auth_request_package(bytes: &[u8; USERNAME_LENGTH + PASSWORD_LENGTH]) -> (String , String) {
-    let (left, right) = bytes.split_at(USERNAME_LENGTH);
-    let mut username_array = [0u8; USERNAME_LENGTH];
-    let mut password_array = [0u8; PASSWORD_LENGTH];
+impl AuthFailure {
+    pub fn to_u8(&self) -> u8 {
+        match self {
+            AuthFailure::InvalidCredentials => 1,
+            AuthFailure::InvalidSession => 2,
+            AuthFailure::LockedOut { .. } => 3,
+            AuthFailure::Unknown(value) => *value,
+        }
+    }
+}
 
-    username_array.copy_from_slice(left);
-    password_array.copy_from_slice(right);
+impl fmt::Display for AuthFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthFailure::InvalidCredentials => write!(f, "wrong username or password"),
+            AuthFailure::InvalidSession => write!(f, "unknown or expired session"),
+            AuthFailure::LockedOut { retry_after_secs } => write!(f, "locked out after too many failed attempts, try again in {}s", retry_after_secs),
+            AuthFailure::Unknown(value) => write!(f, "reason code {}", value),
+        }
+    }
+}
 
-    (String::from_utf8(username_array.to_vec()).unwrap(), String::from_utf8(password_array.to_vec()).unwrap())
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketHeader {
+    pub version: u8,
+    pub encoding: u8,
+    pub data_type: DataType,
 }
 
-pub fn create_auth_request_package(username: String, password: String) -> [u8; PACKET_INFO_SIZE + AUTH_REQUEST_SIZE] {
-    let version: u8 = AUTH_REQUEST_VERSION;
-    let encoding: u8 = 0;
-    let encoding_and_data_type: u8 = (encoding << 6) as u8 | (DataType::AuthRequest.to_u8() & 0x3F);
+impl PacketHeader {
+    pub fn new(version: u8, data_type: DataType) -> Self {
+        PacketHeader {
+            version,
+            encoding: 0,
+            data_type,
+        }
+    }
+
+    pub fn with_encoding(mut self, encoding: PayloadEncoding) -> Self {
+        self.encoding = encoding.to_u8();
+        self
+    }
+
+    pub fn from_bytes(bytes: [u8; PACKET_INFO_SIZE]) -> Self {
+        let version: u8 = bytes[0];
+        let encoding_and_type: u8 = bytes[1];
+        let encoding: u8 = encoding_and_type >> 6;
+        let data_type: DataType = DataType::from_u8(encoding_and_type & 0x3F);
 
-    let mut response_array = [0u8; PACKET_INFO_SIZE + AUTH_REQUEST_SIZE];
-    response_array[0] = version;
-    response_array[1] = encoding_and_data_type;
+        PacketHeader {
+            version,
+            encoding,
+            data_type,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; PACKET_INFO_SIZE] {
+        let encoding_and_data_type: u8 = (self.encoding << 6) | (self.data_type.to_u8() & 0x3F);
 
-    let username_bytes = username.as_bytes();
-    let username_len = username_bytes.len().min(USERNAME_LENGTH);
-    let username_field_start = PACKET_INFO_SIZE;
-    let username_padding = USERNAME_LENGTH - username_len;
-    let username_start_index = username_field_start + username_padding;
+        [self.version, encoding_and_data_type]
+    }
+
+    // Size of the header including the payload length field.
+    pub fn info_size(&self) -> Result<usize, ProtocolError> {
+        match self.version {
+            LENGTH_PREFIXED_VERSION => Ok(LENGTH_PREFIXED_INFO_SIZE),
+            FIXED_LAYOUT_VERSION => Ok(PACKET_INFO_SIZE),
+            version => Err(ProtocolError::BadVersion(version)),
+        }
+    }
+
+    // Payload size of the version 1 layouts, those have no length field on the wire.
+    pub fn fixed_payload_size(&self) -> Option<usize> {
+        match self.data_type {
+            DataType::AuthRequest if self.version == AUTH_REQUEST_VERSION => Some(AUTH_REQUEST_SIZE),
+            DataType::AuthResponse if self.version == AUTH_RESPONSE_VERSION => Some(AUTH_RESPONSE_SIZE),
+            DataType::Ping | DataType::Disconnect if self.version == GAME_PACKET_VERSION => Some(0),
+            DataType::GameData if self.version == GAME_PACKET_VERSION => Some(GAME_PACKET_SIZE),
+            _ => None,
+        }
+    }
+}
 
-    response_array[username_start_index..username_start_index + username_len].copy_from_slice(&username_bytes[..username_len]);
+// Reads the payload length of a length prefixed frame and whether a checksum follows the payload.
+// The bytes must start with the header.
+pub fn read_length_field(bytes: &[u8]) -> Result<(usize, bool), ProtocolError> {
+    let length_bytes = bytes.get(PACKET_INFO_SIZE..LENGTH_PREFIXED_INFO_SIZE).ok_or(ProtocolError::Truncated {
+        expected: LENGTH_PREFIXED_INFO_SIZE,
+        actual: bytes.len(),
+    })?;
+    let length_field = u32::from_be_bytes([length_bytes[0], length_bytes[1], length_bytes[2], length_bytes[3]]);
+    Ok(((length_field & !CHECKSUM_FLAG) as usize, length_field & CHECKSUM_FLAG != 0))
+}
 
-    let password_bytes = password.as_bytes();
-    let password_len = password_bytes.len().min(PASSWORD_LENGTH);
-    let password_field_start = PACKET_INFO_SIZE + USERNAME_LENGTH;
-    let password_padding = PASSWORD_LENGTH - password_len;
-    let password_start_index = password_field_start + password_padding;
+// Marks a length prefixed frame as checksummed and appends the CRC32 of everything before the trailer.
+// Fixed layout frames have no length field to carry the flag and are returned unchanged.
+pub fn append_checksum(mut frame: Vec<u8>) -> Vec<u8> {
+    if frame.len() < LENGTH_PREFIXED_INFO_SIZE || frame[0] != LENGTH_PREFIXED_VERSION || frame[PACKET_INFO_SIZE] & 0x80 != 0 {
+        return frame;
+    }
+    frame[PACKET_INFO_SIZE] |= 0x80;
+    let checksum = crc32fast::hash(&frame);
+    frame.extend_from_slice(&checksum.to_be_bytes());
+    frame
+}
 
-    response_array[password_start_index..password_start_index + password_len].copy_from_slice(&password_bytes[..password_len]);
+fn frame_slice(bytes: &[u8], start: usize, length: usize) -> Result<&[u8], ProtocolError> {
+    let end = start.saturating_add(length);
+    bytes.get(start..end).ok_or(ProtocolError::Truncated {
+        expected: end,
+        actual: bytes.len(),
+    })
+}
 
-    response_array
+// The fixed layout only has room for the u16 value, the length prefixed layout appends the extra bytes after it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GameData {
+    pub value: u16,
+    pub extra: Vec<u8>,
 }
 
-pub fn unpack_auth_response_package(bytes: &[u8; AUTH_RESPONSE_SIZE]) -> String {
-    match String::from_utf8(bytes.to_vec()) {
-        Ok(valid_string) => {
-            return valid_string;
+impl GameData {
+    pub fn new(value: u16) -> Self {
+        GameData {
+            value,
+            extra: Vec::new(),
         }
-        Err(_e) => {
-            return '0'.to_string();
+    }
+
+    pub fn with_extra(value: u16, extra: Vec<u8>) -> Self {
+        GameData {
+            value,
+            extra,
         }
     }
 }
 
-pub fn create_auth_response_package(token: String) -> [u8; PACKET_INFO_SIZE + AUTH_RESPONSE_SIZE] {
-    let version: u8 = AUTH_RESPONSE_VERSION;
-    let encoding: u8 = 0;
-    let encoding_and_data_type: u8 = (encoding << 6) as u8 | (DataType::AuthResponse.to_u8() & 0x3F);
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Packet {
+    AuthRequest { username: String, password: String },
+    // A failed attempt carries AUTH_FAILED_TOKEN and, from version 2 peers, the reason.
+    AuthResponse { token: String, failure: Option<AuthFailure> },
+    Ping,
+    Disconnect { reason: DisconnectReason },
+    GameData(GameData),
+    Hello { versions: Vec<u8> },
+    // The challenge binds encrypted frames to this connection, servers from before it send none.
+    HelloAck { version: u8, challenge: Vec<u8> },
+    HelloReject { versions: Vec<u8> },
+    // Application packets the sender wants acknowledged. The epoch changes whenever the sender
+    // restarts its numbering so the receiver can tell a fresh start from a duplicate.
+    Sequenced { epoch: u32, sequence: u32, packet: Box<Packet> },
+    // Acknowledges every sequence up to and including this one.
+    Ack { sequence: u32 },
+    // Sent instead of AuthRequest after a reconnect, answered with an AuthResponse like a login.
+    ResumeSession { token: String },
+    // Sent by the server to a quiet client, the client sends one back to show it is still there.
+    Heartbeat,
+    // Text to everyone logged in, or to one user when recipient is set. Clients leave sender and timestamp empty,
+    // the server fills them in when relaying. An empty sender marks a notice from the server itself.
+    ChatMessage { recipient: Option<String>, sender: String, timestamp: u64, text: String },
+}
+
+impl Packet {
+    pub fn auth_failure(failure: AuthFailure) -> Packet {
+        Packet::AuthResponse {
+            token: AUTH_FAILED_TOKEN.to_string(),
+            failure: Some(failure),
+        }
+    }
+
+    pub fn data_type(&self) -> DataType {
+        match self {
+            Packet::AuthRequest { .. } => DataType::AuthRequest,
+            Packet::AuthResponse { .. } => DataType::AuthResponse,
+            Packet::Ping => DataType::Ping,
+            Packet::Disconnect { .. } => DataType::Disconnect,
+            Packet::GameData(_) => DataType::GameData,
+            Packet::Hello { .. } => DataType::Hello,
+            Packet::HelloAck { .. } => DataType::HelloAck,
+            Packet::HelloReject { .. } => DataType::HelloReject,
+            Packet::Sequenced { .. } => DataType::Sequenced,
+            Packet::Ack { .. } => DataType::Ack,
+            Packet::ResumeSession { .. } => DataType::ResumeSession,
+            Packet::Heartbeat => DataType::Heartbeat,
+            Packet::ChatMessage { .. } => DataType::ChatMessage,
+        }
+    }
+
+    pub fn header(&self) -> PacketHeader {
+        PacketHeader::new(LENGTH_PREFIXED_VERSION, self.data_type())
+    }
+
+    // Authentication packets carry secrets and are encrypted whenever a key is available.
+    pub fn is_sensitive(&self) -> bool {
+        match self {
+            Packet::AuthRequest { .. } | Packet::AuthResponse { .. } | Packet::ResumeSession { .. } => true,
+            Packet::Sequenced { packet, .. } => packet.is_sensitive(),
+            _ => false,
+        }
+    }
+
+    pub fn encode_as(&self, encoding: PayloadEncoding, key: Option<&EncryptionKey>) -> Result<Vec<u8>, ProtocolError> {
+        let header = self.header().with_encoding(encoding);
+        let header_bytes = header.to_bytes();
+        let payload = encoding::apply_encoding(encoding, &self.encode_payload(), &header_bytes, key)?;
+        Ok(encode_frame(header_bytes, &payload))
+    }
 
-    let mut response_array = [0u8; PACKET_INFO_SIZE + AUTH_RESPONSE_SIZE];
-    response_array[0] = version;
-    response_array[1] = encoding_and_data_type;
+    pub fn decode_with(bytes: &[u8], key: Option<&EncryptionKey>, max_payload_size: usize) -> Result<Self, ProtocolError> {
+        if bytes.len() < PACKET_INFO_SIZE {
+            return Err(ProtocolError::Truncated {
+                expected: PACKET_INFO_SIZE,
+                actual: bytes.len(),
+            });
+        }
+        let header_bytes = [bytes[0], bytes[1]];
+        let header = PacketHeader::from_bytes(header_bytes);
+        let raw_data_type = header_bytes[1] & 0x3F;
+        let info_size = header.info_size()?;
+        let encoding = PayloadEncoding::from_u8(header.encoding).ok_or(ProtocolError::UnknownEncoding(header.encoding))?;
+        if header.version == LENGTH_PREFIXED_VERSION {
+            let (payload_size, has_checksum) = read_length_field(bytes)?;
+            if payload_size > max_payload_size {
+                return Err(ProtocolError::OversizePayload {
+                    size: payload_size,
+                    max: max_payload_size,
+                });
+            }
+            let payload = frame_slice(bytes, info_size, payload_size)?;
+            if has_checksum {
+                let trailer = frame_slice(bytes, info_size + payload_size, CHECKSUM_SIZE)?;
+                let expected = u32::from_be_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+                let actual = crc32fast::hash(&bytes[..info_size + payload_size]);
+                if expected != actual {
+                    return Err(ProtocolError::ChecksumMismatch { expected, actual });
+                }
+            }
+            let payload = encoding::remove_encoding(encoding, payload, &header_bytes, key, max_payload_size)?;
+            Packet::decode_payload(header.data_type, raw_data_type, &payload, key, max_payload_size)
+        } else if encoding == PayloadEncoding::Raw {
+            let payload_size = header.fixed_payload_size().ok_or(ProtocolError::UnknownType(raw_data_type))?;
+            let payload = frame_slice(bytes, info_size, payload_size)?;
+            Packet::decode_fixed_payload(header.data_type, raw_data_type, payload)
+        } else {
+            Err(ProtocolError::UnknownEncoding(header.encoding))
+        }
+    }
 
-    let token_bytes = token.as_bytes();
-    let token_len = token_bytes.len().min(AUTH_RESPONSE_SIZE);
-    let start_index = PACKET_INFO_SIZE + (AUTH_RESPONSE_SIZE - token_len);
+    pub(crate) fn encode_payload(&self) -> Vec<u8> {
+        let mut payload = PayloadWriter::new();
+        match self {
+            Packet::AuthRequest { username, password } => {
+                payload.write_str(username);
+                payload.write_str(password);
+            }
+            Packet::AuthResponse { token, failure } => {
+                payload.write_str(token);
+                if let Some(failure) = failure {
+                    payload.write_u8(failure.to_u8());
+                    if let AuthFailure::LockedOut { retry_after_secs } = failure {
+                        payload.write_u32(*retry_after_secs);
+                    }
+                }
+            }
+            Packet::ResumeSession { token } => {
+                payload.write_str(token);
+            }
+            Packet::Ping | Packet::Heartbeat => {}
+            Packet::Disconnect { reason } => {
+                payload.write_u8(reason.to_u8());
+            }
+            Packet::GameData(game_data) => {
+                payload.write_u16(game_data.value);
+                payload.write_remaining(&game_data.extra);
+            }
+            Packet::Hello { versions } | Packet::HelloReject { versions } => {
+                payload.write_bytes(versions);
+            }
+            Packet::HelloAck { version, challenge } => {
+                payload.write_u8(*version);
+                payload.write_remaining(challenge);
+            }
+            Packet::Sequenced { epoch, sequence, packet } => {
+                payload.write_u32(*epoch);
+                payload.write_u32(*sequence);
+                payload.write_remaining(&packet.encode());
+            }
+            Packet::Ack { sequence } => {
+                payload.write_u32(*sequence);
+            }
+            Packet::ChatMessage { recipient, sender, timestamp, text } => {
+                payload.write_str(recipient.as_deref().unwrap_or_default());
+                payload.write_str(sender);
+                payload.write_u64(*timestamp);
+                payload.write_str(text);
+            }
+        }
+        payload.into_bytes()
+    }
 
-    response_array[start_index..].copy_from_slice(token_bytes);
+    fn decode_payload(data_type: DataType, raw_data_type: u8, payload: &[u8], key: Option<&EncryptionKey>, max_payload_size: usize) -> Result<Self, ProtocolError> {
+        let mut reader = PayloadReader::new(payload);
+        match data_type {
+            DataType::AuthRequest => Ok(Packet::AuthRequest {
+                username: reader.read_str()?,
+                password: reader.read_str()?,
+            }),
+            DataType::AuthResponse => {
+                let token = reader.read_str()?;
+                let failure = match reader.remaining() {
+                    0 => None,
+                    _ => Some(match reader.read_u8()? {
+                        1 => AuthFailure::InvalidCredentials,
+                        2 => AuthFailure::InvalidSession,
+                        3 => AuthFailure::LockedOut {
+                            retry_after_secs: reader.read_u32()?,
+                        },
+                        value => AuthFailure::Unknown(value),
+                    }),
+                };
+                Ok(Packet::AuthResponse { token, failure })
+            }
+            DataType::Ping => Ok(Packet::Ping),
+            // Builds from before the reason code send an empty payload.
+            DataType::Disconnect if reader.remaining() == 0 => Ok(Packet::Disconnect {
+                reason: DisconnectReason::Unspecified,
+            }),
+            DataType::Disconnect => Ok(Packet::Disconnect {
+                reason: DisconnectReason::from_u8(reader.read_u8()?),
+            }),
+            DataType::GameData => Ok(Packet::GameData(GameData {
+                value: reader.read_u16()?,
+                extra: reader.read_remaining().to_vec(),
+            })),
+            DataType::Hello => Ok(Packet::Hello {
+                versions: reader.read_bytes()?.to_vec(),
+            }),
+            DataType::HelloAck => Ok(Packet::HelloAck {
+                version: reader.read_u8()?,
+                challenge: reader.read_remaining().to_vec(),
+            }),
+            DataType::HelloReject => Ok(Packet::HelloReject {
+                versions: reader.read_bytes()?.to_vec(),
+            }),
+            DataType::Sequenced => {
+                let epoch = reader.read_u32()?;
+                let sequence = reader.read_u32()?;
+                let inner = reader.read_remaining();
+                if inner.get(1).map(|encoding_and_type| DataType::from_u8(encoding_and_type & 0x3F)) == Some(DataType::Sequenced) {
+                    return Err(ProtocolError::NestedSequence);
+                }
+                // The inner frame is held to the same limits as the one around it.
+                Ok(Packet::Sequenced {
+                    epoch,
+                    sequence,
+                    packet: Box::new(Packet::decode_with(inner, key, max_payload_size)?),
+                })
+            }
+            DataType::Ack => Ok(Packet::Ack {
+                sequence: reader.read_u32()?,
+            }),
+            DataType::ResumeSession => Ok(Packet::ResumeSession {
+                token: reader.read_str()?,
+            }),
+            DataType::Heartbeat => Ok(Packet::Heartbeat),
+            DataType::ChatMessage => {
+                let recipient = reader.read_str()?;
+                Ok(Packet::ChatMessage {
+                    recipient: Some(recipient).filter(|recipient| !recipient.is_empty()),
+                    sender: reader.read_str()?,
+                    timestamp: reader.read_u64()?,
+                    text: reader.read_str()?,
+                })
+            }
+            DataType::Unknown => Err(ProtocolError::UnknownType(raw_data_type)),
+        }
+    }
+
+    fn decode_fixed_payload(data_type: DataType, raw_data_type: u8, payload: &[u8]) -> Result<Self, ProtocolError> {
+        match data_type {
+            DataType::AuthRequest => {
+                let (username, password) = payload.split_at(USERNAME_LENGTH);
+                Ok(Packet::AuthRequest {
+                    username: Username::from_bytes(username)?.into_string(),
+                    password: Password::from_bytes(password)?.into_string(),
+                })
+            }
+            DataType::AuthResponse => Ok(Packet::AuthResponse {
+                token: AuthToken::from_bytes(payload)?.into_string(),
+                failure: None,
+            }),
+            DataType::Ping => Ok(Packet::Ping),
+            DataType::Disconnect => Ok(Packet::Disconnect {
+                reason: DisconnectReason::Unspecified,
+            }),
+            DataType::GameData => Ok(Packet::GameData(GameData::new(u16::from_be_bytes([payload[0], payload[1]])))),
+            _ => Err(ProtocolError::UnknownType(raw_data_type)),
+        }
+    }
 
-    response_array
+    // Builds the version 1 fixed size layout, kept for peers that do not understand length prefixes.
+    // Packets added after version 1 have no fixed layout.
+    pub fn encode_fixed(&self) -> Result<Vec<u8>, ProtocolError> {
+        let version = match self {
+            Packet::AuthRequest { .. } => AUTH_REQUEST_VERSION,
+            Packet::AuthResponse { .. } => AUTH_RESPONSE_VERSION,
+            Packet::GameData(game_data) if !game_data.extra.is_empty() => {
+                return Err(ProtocolError::NoFixedLayout(self.data_type().to_u8()));
+            }
+            Packet::Ping | Packet::Disconnect { .. } | Packet::GameData(_) => GAME_PACKET_VERSION,
+            Packet::Hello { .. } | Packet::HelloAck { .. } | Packet::HelloReject { .. } | Packet::Sequenced { .. } | Packet::Ack { .. } | Packet::ResumeSession { .. } | Packet::Heartbeat | Packet::ChatMessage { .. } => {
+                return Err(ProtocolError::NoFixedLayout(self.data_type().to_u8()));
+            }
+        };
+        let mut bytes = PacketHeader::new(version, self.data_type()).to_bytes().to_vec();
+        match self {
+            Packet::AuthRequest { username, password } => {
+                bytes.extend_from_slice(&Username::new(username)?.to_bytes());
+                bytes.extend_from_slice(&Password::new(password)?.to_bytes());
+            }
+            Packet::AuthResponse { token, .. } => {
+                bytes.extend_from_slice(&AuthToken::new(token)?.to_bytes());
+            }
+            Packet::GameData(game_data) => {
+                bytes.extend_from_slice(&game_data.value.to_be_bytes());
+            }
+            _ => {}
+        }
+        Ok(bytes)
+    }
 }
 
-pub fn create_empty_package(data_type: DataType) -> [u8; PACKET_INFO_SIZE] {
-    let version: u8 = GAME_PACKET_VERSION;
-    let encoding: u8 = 0;
-    let encoding_and_data_type: u8 = (encoding << 6) as u8 | (data_type.to_u8() & 0x3F);
+pub fn negotiate_version(ours: &[u8], theirs: &[u8]) -> Option<u8> {
+    ours.iter().filter(|version| theirs.contains(version)).max().copied()
+}
 
-    [version, encoding_and_data_type]
+pub trait Encode {
+    fn encode(&self) -> Vec<u8>;
 }
 
-pub fn unpack_game_package(bytes: [u8; GAME_PACKET_SIZE]) -> (u8, u8, DataType, u16) {
-    let version: u8 = bytes[0];
-    let encoding_and_type: u8 = bytes[1];
-    let encoding: u8 = encoding_and_type >> 6;
-    let data_type: DataType = DataType::from_u8(encoding_and_type & 0x3F);
+pub trait Decode: Sized {
+    fn decode(bytes: &[u8]) -> Result<Self, ProtocolError>;
+}
 
-    let data: u16 = ((bytes[GAME_PACKET_SIZE - 2] as u16) << 8) | (bytes[GAME_PACKET_SIZE - 1] as u16);
-    (version, encoding, data_type, data)
+impl Encode for Packet {
+    fn encode(&self) -> Vec<u8> {
+        encode_frame(self.header().to_bytes(), &self.encode_payload())
+    }
 }
 
-pub fn create_game_package(data_type: DataType, content: u16) -> [u8; PACKET_INFO_SIZE + GAME_PACKET_SIZE] {
-    let version: u8 = GAME_PACKET_VERSION;
-    let encoding: u8 = 0;
-    let encoding_and_data_type: u8 = (encoding << 6) as u8 | (data_type.to_u8() & 0x3F);
-    let data: [u8; 2] = [(content >> 8) as u8, (content &0xFF) as u8];
+impl Decode for Packet {
+    fn decode(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        Packet::decode_with(bytes, None, DEFAULT_MAX_PAYLOAD_SIZE)
+    }
+}
 
-    [version, encoding_and_data_type, data[0], data[1]]
+fn encode_frame(header_bytes: [u8; PACKET_INFO_SIZE], payload: &[u8]) -> Vec<u8> {
+    let mut bytes = header_bytes.to_vec();
+    bytes.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(payload);
+    bytes
 }
 
 // 128 64 32 16 8 4 2 1
@@ -163,3 +654,12 @@ pub fn create_game_package(data_type: DataType, content: u16) -> [u8; PACKET_INF
 //├---------------┼---┬-----------┼---------------┴---------------┤
 //|   version     |en | data_type | data                          |
 //└---------------┴---┴-----------┴-------------------------------┘
+//
+//length prefixed package (version 2), payload length is a big endian u32
+//┌---------------┬---------------┬---------------┬---------------┬---------------┬---------------┬--------
+//|1 2 3 4 5 6 7 8|1 2 3 4 5 6 7 8|1 2 3 4 5 6 7 8|1 2 3 4 5 6 7 8|1 2 3 4 5 6 7 8|1 2 3 4 5 6 7 8| ...
+//├---------------┼---┬-----------┼---------------┴---------------┴---------------┴---------------┼--------
+//|   version     |en | data_type | payload length                                                | payload
+//└---------------┴---┴-----------┴---------------------------------------------------------------┴--------
+//
+//when the top bit of the payload length is set a big endian CRC32 of header, length and payload follows the payload
@@ -0,0 +1,117 @@
+use crate::ProtocolError;
+
+// Field helpers for length-prefixed payloads. Integers are big endian,
+// strings and byte blobs carry a u16 length in front of their bytes.
+#[derive(Debug, Default)]
+pub struct PayloadWriter {
+    bytes: Vec<u8>,
+}
+
+impl PayloadWriter {
+    pub fn new() -> Self {
+        PayloadWriter {
+            bytes: Vec::new(),
+        }
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+    }
+
+    pub fn write_bytes(&mut self, value: &[u8]) {
+        let length = value.len().min(u16::MAX as usize);
+        self.write_u16(length as u16);
+        self.bytes.extend_from_slice(&value[..length]);
+    }
+
+    // Appends bytes without a length, only valid as the last field of a payload.
+    pub fn write_remaining(&mut self, value: &[u8]) {
+        self.bytes.extend_from_slice(value);
+    }
+
+    pub fn write_str(&mut self, value: &str) {
+        let mut length = value.len().min(u16::MAX as usize);
+        while !value.is_char_boundary(length) {
+            length -= 1;
+        }
+        self.write_bytes(&value.as_bytes()[..length]);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+#[derive(Debug)]
+pub struct PayloadReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> PayloadReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        PayloadReader {
+            bytes,
+            position: 0,
+        }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.bytes.len() - self.position
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], ProtocolError> {
+        let end = self.position.saturating_add(length);
+        let slice = self.bytes.get(self.position..end).ok_or(ProtocolError::Truncated {
+            expected: end,
+            actual: self.bytes.len(),
+        })?;
+        self.position = end;
+        Ok(slice)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, ProtocolError> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, ProtocolError> {
+        self.take(2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, ProtocolError> {
+        self.take(4).map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, ProtocolError> {
+        let mut value = [0u8; 8];
+        value.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(value))
+    }
+
+    pub fn read_bytes(&mut self) -> Result<&'a [u8], ProtocolError> {
+        let length = self.read_u16()? as usize;
+        self.take(length)
+    }
+
+    pub fn read_remaining(&mut self) -> &'a [u8] {
+        let remaining = &self.bytes[self.position..];
+        self.position = self.bytes.len();
+        remaining
+    }
+
+    pub fn read_str(&mut self) -> Result<String, ProtocolError> {
+        String::from_utf8(self.read_bytes()?.to_vec()).map_err(|_| ProtocolError::InvalidUtf8)
+    }
+}
@@ -0,0 +1,198 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+// A CIDR block like 10.0.0.0/8 or ::1/128, a bare address is a block of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNetwork {
+    address: IpAddr,
+    prefix_length: u8,
+}
+
+impl IpNetwork {
+    // Host bits are cleared, so 10.1.2.3/8 is stored and printed as 10.0.0.0/8. Blocks inside ::ffff:0:0/96
+    // become IPv4 blocks, ::ffff:10.0.0.0/104 is 10.0.0.0/8, because peers are compared in their IPv4 form.
+    pub fn new(address: IpAddr, prefix_length: u8) -> Result<Self, String> {
+        let (address, prefix_length) = match address {
+            IpAddr::V6(v6) if (96..=128).contains(&prefix_length) => match v6.to_ipv4_mapped() {
+                Some(v4) => (IpAddr::V4(v4), prefix_length - 96),
+                None => (address, prefix_length),
+            },
+            _ => (address, prefix_length),
+        };
+        let address = match address {
+            IpAddr::V4(v4) if prefix_length <= 32 => IpAddr::V4(Ipv4Addr::from(u32::from(v4) & v4_mask(prefix_length))),
+            IpAddr::V6(v6) if prefix_length <= 128 => IpAddr::V6(Ipv6Addr::from(u128::from(v6) & v6_mask(prefix_length))),
+            IpAddr::V4(_) => return Err(format!("prefix length {} is longer than 32", prefix_length)),
+            IpAddr::V6(_) => return Err(format!("prefix length {} is longer than 128", prefix_length)),
+        };
+        Ok(IpNetwork {
+            address,
+            prefix_length,
+        })
+    }
+
+    // IPv4 peers that show up as ::ffff:a.b.c.d on a dual stack socket match IPv4 blocks.
+    pub fn contains(&self, address: IpAddr) -> bool {
+        match (self.address, address.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                u32::from(address) & v4_mask(self.prefix_length) == u32::from(network)
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                u128::from(address) & v6_mask(self.prefix_length) == u128::from(network)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn v4_mask(prefix_length: u8) -> u32 {
+    u32::MAX.checked_shl(32 - prefix_length as u32).unwrap_or(0)
+}
+
+fn v6_mask(prefix_length: u8) -> u128 {
+    u128::MAX.checked_shl(128 - prefix_length as u32).unwrap_or(0)
+}
+
+impl FromStr for IpNetwork {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim();
+        let (address, prefix_length) = match value.split_once('/') {
+            Some((address, prefix_length)) => {
+                let prefix_length = prefix_length.parse().map_err(|_| format!("invalid prefix length in '{}'", value))?;
+                (address, Some(prefix_length))
+            }
+            None => (value, None),
+        };
+        let address: IpAddr = address.parse().map_err(|_| format!("invalid address in '{}'", value))?;
+        let prefix_length = prefix_length.unwrap_or(if address.is_ipv4() { 32 } else { 128 });
+        IpNetwork::new(address, prefix_length)
+    }
+}
+
+impl fmt::Display for IpNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix_length)
+    }
+}
+
+impl Serialize for IpNetwork {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for IpNetwork {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        value.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Denial {
+    DenyRule(IpNetwork),
+    NotAllowed,
+}
+
+impl fmt::Display for Denial {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Denial::DenyRule(network) => write!(f, "matches deny rule {}", network),
+            Denial::NotAllowed => write!(f, "not covered by any allow rule"),
+        }
+    }
+}
+
+// Deny rules win over allow rules. An empty allow list lets in everyone who is not denied.
+#[derive(Debug, Clone)]
+pub struct AccessControl {
+    allow: Vec<IpNetwork>,
+    deny: Vec<IpNetwork>,
+}
+
+impl AccessControl {
+    pub fn new(allow: Vec<IpNetwork>, deny: Vec<IpNetwork>) -> Self {
+        AccessControl {
+            allow,
+            deny,
+        }
+    }
+
+    pub fn check(&self, address: IpAddr) -> Result<(), Denial> {
+        if let Some(network) = self.deny.iter().find(|network| network.contains(address)) {
+            return Err(Denial::DenyRule(*network));
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(|network| network.contains(address)) {
+            return Err(Denial::NotAllowed);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network(value: &str) -> IpNetwork {
+        value.parse().unwrap()
+    }
+
+    fn address(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn host_bits_are_masked() {
+        assert_eq!(network("10.1.2.3/8").to_string(), "10.0.0.0/8");
+        assert_eq!(network("192.168.77.200/20").to_string(), "192.168.64.0/20");
+        assert_eq!(network("2001:db8::1/32").to_string(), "2001:db8::/32");
+        assert_eq!(network("10.1.2.3/8"), network("10.0.0.0/8"));
+    }
+
+    #[test]
+    fn whole_range_and_single_hosts() {
+        assert!(network("0.0.0.0/0").contains(address("203.0.113.9")));
+        assert!(network("::/0").contains(address("2001:db8::1")));
+        assert!(network("10.0.0.1/32").contains(address("10.0.0.1")));
+        assert!(!network("10.0.0.1/32").contains(address("10.0.0.2")));
+        assert!(network("::1/128").contains(address("::1")));
+        assert!(!network("::1/128").contains(address("::2")));
+        // A bare address is a block of one.
+        assert_eq!(network("10.0.0.1"), network("10.0.0.1/32"));
+        assert_eq!(network("::1"), network("::1/128"));
+        assert!("10.0.0.0/33".parse::<IpNetwork>().is_err());
+        assert!("::/129".parse::<IpNetwork>().is_err());
+    }
+
+    #[test]
+    fn mapped_peers_match_ipv4_blocks() {
+        assert!(network("10.0.0.0/8").contains(address("::ffff:10.20.30.40")));
+        assert!(!network("10.0.0.0/8").contains(address("::ffff:11.0.0.1")));
+        assert_eq!(network("::ffff:10.0.0.0/104"), network("10.0.0.0/8"));
+        // Families do not mix otherwise.
+        assert!(!network("::/0").contains(address("10.0.0.1")));
+        assert!(!network("0.0.0.0/0").contains(address("2001:db8::1")));
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let access = AccessControl::new(vec![network("10.0.0.0/8")], vec![network("10.0.5.0/24")]);
+        assert_eq!(access.check(address("10.0.4.1")), Ok(()));
+        assert_eq!(access.check(address("10.0.5.1")), Err(Denial::DenyRule(network("10.0.5.0/24"))));
+        assert_eq!(access.check(address("::ffff:10.0.5.1")), Err(Denial::DenyRule(network("10.0.5.0/24"))));
+        assert_eq!(access.check(address("192.0.2.1")), Err(Denial::NotAllowed));
+    }
+
+    #[test]
+    fn empty_allow_list_lets_everyone_in_who_is_not_denied() {
+        let access = AccessControl::new(Vec::new(), vec![network("192.0.2.0/24")]);
+        assert_eq!(access.check(address("203.0.113.9")), Ok(()));
+        assert_eq!(access.check(address("2001:db8::1")), Ok(()));
+        assert_eq!(access.check(address("192.0.2.1")), Err(Denial::DenyRule(network("192.0.2.0/24"))));
+        assert_eq!(AccessControl::new(Vec::new(), Vec::new()).check(address("::1")), Ok(()));
+    }
+}
@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use futures_util::{SinkExt, StreamExt};
+use log::{error, info, warn};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::mpsc::{self, Sender};
+use tokio::sync::Notify;
+use tokio::time::{sleep_until, Instant};
+use tokio_util::codec::Framed;
+use config::{DecodedFrame, EncryptionKey, FrameDecoder, FrameEncoder, PacketCodec};
+use crate::checksum::{log_decoder_losses, ChecksumPolicy};
+use crate::login::LoginWorker;
+use crate::outbound::OutboundQueue;
+use crate::settings::Settings;
+use crate::state::{ConnectionId, Outgoing, ServerState};
+
+// Frames decoded but not yet handled by the state task. Once it is full connection tasks wait
+// before reading on, so a flood backs up into the sockets instead of memory.
+const INCOMING_CAPACITY: usize = 1024;
+
+enum ConnectionMessage {
+    Frame(ConnectionId, DecodedFrame),
+    Closed(ConnectionId),
+}
+
+// Shared by the state task, which queues commands, and the connection task, which writes them out.
+struct ConnectionHandle {
+    outbound: Mutex<OutboundQueue>,
+    ready: Notify,
+}
+
+// Same behaviour as the mio reactor. One task owns the server state, every connection gets a task that
+// decodes frames for it and carries out whatever the state puts in its outbound queue.
+pub async fn run_server(settings: Settings, mut state: ServerState) -> io::Result<()> {
+    let listener = match TcpListener::bind(settings.bind_address).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to bind! {}", e);
+            return Ok(());
+        }
+    };
+    let mut listener = Some(listener);
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut shutdown_deadline = None;
+    info!("Server is running!");
+    let key = EncryptionKey::from_env();
+    let (incoming_sender, mut incoming) = mpsc::channel(INCOMING_CAPACITY);
+    // Password checks run on their own thread like with the mio reactor, the tasks here never block on argon2.
+    // Each connection has at most one in flight, which bounds the answers coming back.
+    let (verified_sender, mut verified_logins) = mpsc::unbounded_channel();
+    let login_worker = LoginWorker::spawn(move |login| {
+        let _ = verified_sender.send(login);
+    })?;
+    let mut connections: HashMap<ConnectionId, Arc<ConnectionHandle>> = HashMap::new();
+    let mut next_connection_id: ConnectionId = 1;
+    let mut sweep_interval = tokio::time::interval(settings.session_sweep_interval());
+    let mut heartbeat_interval = tokio::time::interval(settings.heartbeat_interval());
+    loop {
+        let next_release = state.next_release().map(Instant::from_std);
+        tokio::select! {
+            accepted = accept(&listener) => {
+                let (stream, address) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!("Connection failed! {}", e);
+                        continue;
+                    }
+                };
+                if let Err(denial) = state.check_access(address.ip()) {
+                    warn!("Rejected connection from {}: {}", address, denial);
+                    drop(stream);
+                    continue;
+                }
+                info!("New connection from {}", address);
+                let connection_id = next_connection_id;
+                next_connection_id += 1;
+                let challenge = state.open_connection(connection_id, address);
+                let key = key.as_ref().map(|key| key.bound_to(&challenge));
+                let codec = PacketCodec::new(
+                    FrameDecoder::with_max_payload_size(settings.max_payload_size).with_key(key.clone()).with_resync(state.checksum_policy() == ChecksumPolicy::Resync),
+                    FrameEncoder::new().with_key(key).with_checksum(true),
+                );
+                let handle = Arc::new(ConnectionHandle {
+                    outbound: Mutex::new(OutboundQueue::new(settings.outbound_queue_limit, settings.slow_consumer_policy)),
+                    ready: Notify::new(),
+                });
+                connections.insert(connection_id, handle.clone());
+                tokio::spawn(connection_task(connection_id, Framed::new(stream, codec), handle, incoming_sender.clone()));
+            }
+            Some(message) = incoming.recv() => {
+                match message {
+                    ConnectionMessage::Frame(connection_id, frame) => state.handle_frame(connection_id, frame),
+                    ConnectionMessage::Closed(connection_id) => {
+                        info!("Connection {}: closed.", connection_id);
+                        connections.remove(&connection_id);
+                        state.connection_closed(connection_id);
+                    }
+                }
+            }
+            Some(login) = verified_logins.recv() => state.login_verified(login),
+            _ = sweep_interval.tick() => state.sweep(),
+            _ = heartbeat_interval.tick() => state.check_idle(),
+            _ = sleep_until(next_release.unwrap_or_else(Instant::now)), if next_release.is_some() => state.release_delayed(),
+            _ = signalled(&mut interrupt, &mut terminate) => {
+                if shutdown_deadline.is_some() {
+                    warn!("Signalled again, stopping without waiting for {} connections.", connections.len());
+                    return Ok(());
+                }
+                listener = None;
+                let notified_clients = state.shutdown();
+                info!("Shutting down, disconnecting {} clients.", notified_clients);
+                shutdown_deadline = Some(Instant::now() + settings.shutdown_timeout());
+            }
+            _ = sleep_until(shutdown_deadline.unwrap_or_else(Instant::now)), if shutdown_deadline.is_some() => {
+                warn!("Shutdown deadline passed, dropping {} connections.", connections.len());
+                return Ok(());
+            }
+        }
+        for login in state.take_logins() {
+            login_worker.submit(login);
+        }
+        // A slow consumer makes the state queue its disconnect notice, which goes out in the next round.
+        loop {
+            let outgoing = state.take_outgoing();
+            if outgoing.is_empty() {
+                break;
+            }
+            for (connection_id, outgoing) in outgoing {
+                let Some(connection) = connections.get(&connection_id) else {
+                    continue;
+                };
+                let queued = connection.outbound.lock().unwrap().push(outgoing);
+                connection.ready.notify_one();
+                if queued.is_err() {
+                    state.slow_consumer(connection_id);
+                }
+            }
+        }
+        if shutdown_deadline.is_some() && connections.is_empty() {
+            info!("Server stopped.");
+            return Ok(());
+        }
+    }
+}
+
+// Never resolves once the listener is gone.
+async fn accept(listener: &Option<TcpListener>) -> io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn signalled(interrupt: &mut Signal, terminate: &mut Signal) {
+    tokio::select! {
+        _ = interrupt.recv() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+async fn connection_task(connection_id: ConnectionId, mut framed: Framed<TcpStream, PacketCodec>, handle: Arc<ConnectionHandle>, incoming: Sender<ConnectionMessage>) {
+    loop {
+        tokio::select! {
+            // Writes first, so queued replies go out before more frames are read and answered.
+            biased;
+            _ = handle.ready.notified() => {
+                // Everything already queued goes out with a single flush. Feeding waits for the socket once
+                // the codec buffer is full, whatever arrives meanwhile stays in the bounded queue.
+                let mut close = false;
+                while !close {
+                    let next = handle.outbound.lock().unwrap().pop();
+                    let Some(command) = next else {
+                        break;
+                    };
+                    match command {
+                        Outgoing::Send(packet) => {
+                            let data_type = packet.data_type();
+                            match framed.feed(packet).await {
+                                Ok(()) => {}
+                                // The codec reports packets it cannot encode as InvalidData, only that packet
+                                // is lost, like on the mio server.
+                                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                                    warn!("Connection {}: could not encode {:?}, dropped it: {}", connection_id, data_type, e);
+                                }
+                                Err(e) => {
+                                    warn!("Connection {}: write failed: {}", connection_id, e);
+                                    close = true;
+                                }
+                            }
+                        }
+                        Outgoing::SetVersion(version) => framed.codec_mut().encoder_mut().set_version(version),
+                        Outgoing::Close => close = true,
+                    }
+                }
+                if let Err(e) = framed.flush().await {
+                    warn!("Connection {}: write failed: {}", connection_id, e);
+                    break;
+                }
+                if close {
+                    break;
+                }
+            }
+            frame = framed.next() => {
+                match frame {
+                    Some(Ok(frame)) => {
+                        if incoming.send(ConnectionMessage::Frame(connection_id, frame)).await.is_err() {
+                            break;
+                        }
+                    }
+                    Some(Err(e)) => {
+                        warn!("Connection {}: read failed: {}", connection_id, e);
+                        break;
+                    }
+                    None => break,
+                }
+            }
+        }
+    }
+    log_decoder_losses(connection_id, framed.codec().decoder());
+    let _ = incoming.send(ConnectionMessage::Closed(connection_id)).await;
+}
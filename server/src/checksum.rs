@@ -0,0 +1,37 @@
+use std::str::FromStr;
+use std::sync::atomic::AtomicU64;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use config::FrameDecoder;
+use crate::state::ConnectionId;
+
+pub static CHECKSUM_FAILURES: AtomicU64 = AtomicU64::new(0);
+
+// What the server does with a connection after a frame fails its checksum.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChecksumPolicy {
+    Drop,
+    Resync,
+    Disconnect,
+}
+
+impl FromStr for ChecksumPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "drop" => Ok(ChecksumPolicy::Drop),
+            "resync" => Ok(ChecksumPolicy::Resync),
+            "disconnect" => Ok(ChecksumPolicy::Disconnect),
+            _ => Err(format!("unknown checksum policy '{}', expected drop, resync or disconnect", value)),
+        }
+    }
+}
+
+// What the decoder skipped over on one connection, reported once the connection is gone.
+pub fn log_decoder_losses(connection_id: ConnectionId, decoder: &FrameDecoder) {
+    if decoder.checksum_failures() > 0 || decoder.discarded_bytes() > 0 {
+        warn!("Connection {}: {} checksum failures, {} bytes discarded while decoding.", connection_id, decoder.checksum_failures(), decoder.discarded_bytes());
+    }
+}
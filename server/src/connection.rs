@@ -0,0 +1,112 @@
+use std::io::{self, prelude::*, ErrorKind};
+use log::warn;
+use mio::net::TcpStream;
+use config::{FrameDecoder, FrameEncoder, READ_BUFFER_SIZE};
+use crate::outbound::{OutboundQueue, QueueFull};
+use crate::state::Outgoing;
+
+// Encoded bytes taken off the queue at once, the rest stays droppable until the socket catches up.
+const WRITE_BATCH_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ReadStatus {
+    Received,
+    // Nothing left to read until the reactor reports the socket readable again.
+    Drained,
+    Closed,
+}
+
+// One socket and its framing state. Reads and writes never block, commands wait in the bounded outbound queue
+// and are only encoded into write_buffer once the socket has taken what was there before.
+pub struct Connection {
+    pub stream: TcpStream,
+    pub decoder: FrameDecoder,
+    pub encoder: FrameEncoder,
+    // Set once the close command comes off the queue, the connection goes away when write_buffer is written.
+    pub closing: bool,
+    pub writable_registered: bool,
+    outbound: OutboundQueue,
+    // Whole frames only, so dropping queued packets never cuts one in half.
+    write_buffer: Vec<u8>,
+    written: usize,
+}
+
+impl Connection {
+    pub fn new(stream: TcpStream, decoder: FrameDecoder, encoder: FrameEncoder, outbound: OutboundQueue) -> Self {
+        Connection {
+            stream,
+            decoder,
+            encoder,
+            closing: false,
+            writable_registered: false,
+            outbound,
+            write_buffer: Vec::new(),
+            written: 0,
+        }
+    }
+
+    // Reads one buffer worth into the decoder. Callers keep going until Drained, mio only reports
+    // readiness again after the socket would have blocked.
+    pub fn read_chunk(&mut self) -> io::Result<ReadStatus> {
+        let mut read_buffer = [0u8; READ_BUFFER_SIZE];
+        loop {
+            match self.stream.read(&mut read_buffer) {
+                Ok(0) => return Ok(ReadStatus::Closed),
+                Ok(bytes_read) => {
+                    self.decoder.extend(&read_buffer[..bytes_read]);
+                    return Ok(ReadStatus::Received);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(ReadStatus::Drained),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Err when the queue overflowed under the disconnect policy.
+    pub fn queue(&mut self, outgoing: Outgoing) -> Result<(), QueueFull> {
+        let result = self.outbound.push(outgoing);
+        self.fill_write_buffer();
+        result
+    }
+
+    pub fn has_pending_writes(&self) -> bool {
+        self.written < self.write_buffer.len() || !self.outbound.is_empty()
+    }
+
+    // Writes as much as the socket accepts, a partial write resumes where it stopped once the socket is writable again.
+    pub fn flush(&mut self) -> io::Result<()> {
+        loop {
+            if self.written == self.write_buffer.len() {
+                self.write_buffer.clear();
+                self.written = 0;
+                self.fill_write_buffer();
+                if self.write_buffer.is_empty() {
+                    return Ok(());
+                }
+            }
+            match self.stream.write(&self.write_buffer[self.written..]) {
+                Ok(0) => return Err(io::Error::from(ErrorKind::WriteZero)),
+                Ok(bytes_written) => self.written += bytes_written,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Encodes queued commands while the buffer has room, packets only wait in the queue behind a full buffer.
+    fn fill_write_buffer(&mut self) {
+        while !self.closing && self.write_buffer.len() < WRITE_BATCH_SIZE {
+            match self.outbound.pop() {
+                Some(Outgoing::Send(packet)) => match self.encoder.encode(&packet) {
+                    Ok(bytes) => self.write_buffer.extend_from_slice(&bytes),
+                    Err(e) => warn!("Could not encode {:?}, dropped it: {}", packet.data_type(), e),
+                },
+                Some(Outgoing::SetVersion(version)) => self.encoder.set_version(version),
+                Some(Outgoing::Close) => self.closing = true,
+                None => return,
+            }
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use log::warn;
+use config::{PASSWORD_LENGTH, USERNAME_LENGTH};
+use rand::rngs::OsRng;
+
+// Authentication backends plug in here.
+pub trait CredentialStore: Send + Sync {
+    fn verify(&self, username: &str, password: &str) -> bool;
+}
+
+#[derive(Debug)]
+pub enum CredentialError {
+    InvalidUsername,
+    InvalidPassword,
+    Hashing(argon2::password_hash::Error),
+    Io(io::Error),
+}
+
+impl fmt::Display for CredentialError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CredentialError::InvalidUsername => write!(f, "username must be 1 to {} bytes without ':' or whitespace", USERNAME_LENGTH),
+            CredentialError::InvalidPassword => write!(f, "password must be 1 to {} bytes", PASSWORD_LENGTH),
+            CredentialError::Hashing(e) => write!(f, "could not hash password: {}", e),
+            CredentialError::Io(e) => write!(f, "could not write credentials file: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CredentialError {}
+
+// One "username:argon2 hash" line per user, the hash string carries its own salt and parameters.
+pub struct FileCredentialStore {
+    path: PathBuf,
+    password_hashes: HashMap<String, String>,
+    // Checked against for unknown users so they take as long to reject as a wrong password.
+    dummy_hash: String,
+}
+
+impl FileCredentialStore {
+    // A missing file is an empty store, it gets created by the first add_user.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        let mut password_hashes = HashMap::new();
+        for (line_number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once(':') {
+                Some((username, password_hash)) if PasswordHash::new(password_hash).is_ok() => {
+                    password_hashes.insert(username.to_string(), password_hash.to_string());
+                }
+                _ => warn!("{}:{}: skipping malformed credential entry", path.display(), line_number + 1),
+            }
+        }
+        let dummy_hash = hash_password("dummy password").map_err(|e| io::Error::other(e.to_string()))?;
+        Ok(FileCredentialStore {
+            path,
+            password_hashes,
+            dummy_hash,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn user_count(&self) -> usize {
+        self.password_hashes.len()
+    }
+
+    // Adds or replaces a user and writes the file back.
+    pub fn add_user(&mut self, username: &str, password: &str) -> Result<(), CredentialError> {
+        if username.is_empty() || username.len() > USERNAME_LENGTH || username.contains(|c: char| c == ':' || c.is_whitespace() || c == '\0') {
+            return Err(CredentialError::InvalidUsername);
+        }
+        if password.is_empty() || password.len() > PASSWORD_LENGTH || password.contains('\0') {
+            return Err(CredentialError::InvalidPassword);
+        }
+        let password_hash = hash_password(password).map_err(CredentialError::Hashing)?;
+        self.password_hashes.insert(username.to_string(), password_hash);
+        self.save().map_err(CredentialError::Io)
+    }
+
+    // Written next to the file and renamed over it, so a crash never leaves half a file behind
+    // and the hashes are never readable by other users, not even for a moment.
+    fn save(&self) -> io::Result<()> {
+        let mut usernames: Vec<&String> = self.password_hashes.keys().collect();
+        usernames.sort();
+        let mut contents = String::new();
+        for username in usernames {
+            contents.push_str(&format!("{}:{}\n", username, self.password_hashes[username]));
+        }
+        let mut temp_name = self.path.file_name().unwrap_or_default().to_os_string();
+        temp_name.push(".tmp");
+        let temp_path = self.path.with_file_name(temp_name);
+        // A leftover from an earlier crash may have looser permissions, the mode only applies to new files.
+        match fs::remove_file(&temp_path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let mut file = options.open(&temp_path)?;
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temp_path, &self.path)
+    }
+}
+
+impl CredentialStore for FileCredentialStore {
+    fn verify(&self, username: &str, password: &str) -> bool {
+        let (password_hash, known_user) = match self.password_hashes.get(username) {
+            Some(password_hash) => (password_hash, true),
+            None => (&self.dummy_hash, false),
+        };
+        let Ok(parsed_hash) = PasswordHash::new(password_hash) else {
+            return false;
+        };
+        let password_matches = Argon2::default().verify_password(password.as_bytes(), &parsed_hash).is_ok();
+        known_user && password_matches
+    }
+}
+
+fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default().hash_password(password.as_bytes(), &salt)?.to_string())
+}
@@ -0,0 +1,50 @@
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Delivery {
+    New,
+    Duplicate,
+    // An earlier sequence is missing, the sender will retransmit from there.
+    Gap,
+}
+
+// Remembers the last processed sequence per user and epoch so retransmissions after a reconnect are not applied twice.
+// Every login under a name picks its own epoch, so several of them never share a counter.
+#[derive(Default)]
+pub struct DeliveryTracker {
+    last_sequences: HashMap<(String, u32), u32>,
+}
+
+impl DeliveryTracker {
+    pub fn new() -> Self {
+        DeliveryTracker {
+            last_sequences: HashMap::new(),
+        }
+    }
+
+    pub fn check(&self, username: &str, epoch: u32, sequence: u32) -> Delivery {
+        match self.last_sequences.get(&(username.to_string(), epoch)) {
+            Some(&last_sequence) => {
+                if sequence <= last_sequence {
+                    Delivery::Duplicate
+                } else if sequence == last_sequence + 1 {
+                    Delivery::New
+                } else {
+                    Delivery::Gap
+                }
+            }
+            None => Delivery::New,
+        }
+    }
+
+    pub fn record(&mut self, username: &str, epoch: u32, sequence: u32) {
+        self.last_sequences.insert((username.to_string(), epoch), sequence);
+    }
+
+    // Forgets every stream no remaining client sends on, returns how many were dropped.
+    pub fn retain(&mut self, live: &HashSet<(String, u32)>) -> usize {
+        let before = self.last_sequences.len();
+        self.last_sequences.retain(|stream, _| live.contains(stream));
+        before - self.last_sequences.len()
+    }
+}
@@ -0,0 +1,27 @@
+use log::info;
+use config::GameData;
+
+// Who a piece of game data the logic produced goes to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recipient {
+    // The client the data came from.
+    Sender,
+    Client(usize),
+    // Every logged in client, the sender included.
+    Everyone,
+    EveryoneElse,
+}
+
+// Game logic plugs in here. Players are addressed by client id, the server works out their connections.
+pub trait GameLogic: Send {
+    fn on_game_data(&mut self, client_id: usize, data: GameData) -> Vec<(Recipient, GameData)>;
+}
+
+pub struct LoggingGame;
+
+impl GameLogic for LoggingGame {
+    fn on_game_data(&mut self, client_id: usize, data: GameData) -> Vec<(Recipient, GameData)> {
+        info!("Client {}: game data {} ({} extra bytes)", client_id, data.value, data.extra.len());
+        Vec::new()
+    }
+}
@@ -0,0 +1,52 @@
+// The server as a library, so other services can run it in their own process.
+// The binary only adds the command line, the logger and the add-user command on top.
+mod acl;
+#[cfg(feature = "async")]
+pub mod async_server;
+mod checksum;
+#[cfg(not(feature = "async"))]
+mod connection;
+pub mod credentials;
+mod delivery;
+pub mod game;
+mod lockout;
+mod login;
+mod outbound;
+mod rate_limit;
+mod registry;
+#[cfg(not(feature = "async"))]
+pub mod server;
+mod session;
+pub mod settings;
+pub mod state;
+
+use config::{DisconnectReason, Packet};
+
+#[cfg(feature = "async")]
+pub use async_server::run_server;
+
+// Addressed by client id, the state resolves them against the registry. Clients without a live connection are skipped.
+#[derive(Debug)]
+enum EventType {
+    SendTo(usize, Packet),
+    // To every logged in client.
+    Broadcast(Packet),
+    BroadcastExcept(usize, Packet),
+    // Tells the client why it is disconnected, then closes like Close.
+    Kick(usize, DisconnectReason),
+    // Closes the connection once everything queued before it has been written.
+    Close(usize),
+}
+
+#[derive(Debug)]
+struct Event {
+    pub event_type: EventType,
+}
+
+impl Event {
+    fn new(event_type: EventType) -> Self {
+        Event {
+            event_type,
+        }
+    }
+}
@@ -0,0 +1,183 @@
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use crate::settings::Settings;
+
+struct Failures {
+    count: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+// Failed logins per key. Once a key reaches the threshold every further failure locks it out,
+// for the base duration first and twice as long with each failure after that.
+struct FailureCounter<K> {
+    failures: HashMap<K, Failures>,
+    threshold: u32,
+}
+
+impl<K: Hash + Eq> FailureCounter<K> {
+    fn new(threshold: u32) -> Self {
+        FailureCounter {
+            failures: HashMap::new(),
+            threshold,
+        }
+    }
+
+    fn locked_out<Q: Hash + Eq + ?Sized>(&self, key: &Q, now: Instant) -> Option<Duration>
+    where
+        K: Borrow<Q>,
+    {
+        let locked_until = self.failures.get(key)?.locked_until?;
+        locked_until.checked_duration_since(now).filter(|remaining| !remaining.is_zero())
+    }
+
+    // A key that has not failed for the longest lockout starts counting from zero again.
+    fn record_failure(&mut self, key: K, now: Instant, base_lockout: Duration, max_lockout: Duration) -> Option<Duration> {
+        let failures = self.failures.entry(key).or_insert(Failures {
+            count: 0,
+            last_failure: now,
+            locked_until: None,
+        });
+        if now.saturating_duration_since(failures.last_failure) >= max_lockout {
+            failures.count = 0;
+        }
+        failures.count += 1;
+        failures.last_failure = now;
+        if failures.count < self.threshold {
+            return None;
+        }
+        let doublings = (failures.count - self.threshold).min(31);
+        let lockout = base_lockout.saturating_mul(1 << doublings).min(max_lockout);
+        failures.locked_until = Some(now + lockout);
+        Some(lockout)
+    }
+
+    fn sweep(&mut self, now: Instant, max_lockout: Duration) {
+        self.failures.retain(|_, failures| {
+            let locked = failures.locked_until.is_some_and(|locked_until| locked_until > now);
+            locked || now.saturating_duration_since(failures.last_failure) < max_lockout
+        });
+    }
+}
+
+// Guards password checks against guessing, per username and per source address. Attempts while
+// locked out are refused without checking the password and do not count as failures.
+pub struct LoginGuard {
+    usernames: FailureCounter<String>,
+    addresses: FailureCounter<IpAddr>,
+    base_lockout: Duration,
+    max_lockout: Duration,
+}
+
+impl LoginGuard {
+    pub fn new(username_threshold: u32, address_threshold: u32, base_lockout: Duration, max_lockout: Duration) -> Self {
+        LoginGuard {
+            usernames: FailureCounter::new(username_threshold),
+            addresses: FailureCounter::new(address_threshold),
+            base_lockout,
+            max_lockout,
+        }
+    }
+
+    pub fn from_settings(settings: &Settings) -> Self {
+        LoginGuard::new(settings.lockout_threshold, settings.address_lockout_threshold, settings.lockout_base(), settings.lockout_max())
+    }
+
+    // How much longer the username or the address is locked out, None when a login may be tried.
+    pub fn locked_out(&self, username: &str, address: IpAddr) -> Option<Duration> {
+        let now = Instant::now();
+        let username_lockout = self.usernames.locked_out(username, now);
+        let address_lockout = self.addresses.locked_out(&address.to_canonical(), now);
+        username_lockout.max(address_lockout)
+    }
+
+    // Returns the lockout this failure started, if it started one.
+    pub fn record_failure(&mut self, username: &str, address: IpAddr) -> Option<Duration> {
+        let now = Instant::now();
+        let username_lockout = self.usernames.record_failure(username.to_string(), now, self.base_lockout, self.max_lockout);
+        let address_lockout = self.addresses.record_failure(address.to_canonical(), now, self.base_lockout, self.max_lockout);
+        username_lockout.max(address_lockout)
+    }
+
+    // The address keeps its count, one valid account should not reset guesses at the others.
+    pub fn record_success(&mut self, username: &str) {
+        self.usernames.failures.remove(username);
+    }
+
+    pub fn sweep(&mut self) {
+        let now = Instant::now();
+        self.usernames.sweep(now, self.max_lockout);
+        self.addresses.sweep(now, self.max_lockout);
+    }
+}
+
+// Rounded up, so a client that waits this long is no longer locked out.
+pub fn retry_after_secs(lockout: Duration) -> u32 {
+    let secs = lockout.as_secs() + u64::from(lockout.subsec_nanos() > 0);
+    u32::try_from(secs).unwrap_or(u32::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: Duration = Duration::from_secs(30);
+    const MAX: Duration = Duration::from_secs(300);
+
+    #[test]
+    fn lockout_doubles_up_to_the_maximum() {
+        let mut counter = FailureCounter::new(3);
+        let start = Instant::now();
+        let mut lockouts = Vec::new();
+        for attempt in 0..8 {
+            lockouts.push(counter.record_failure("alice", start + Duration::from_secs(attempt), BASE, MAX));
+        }
+        let secs = |secs| Some(Duration::from_secs(secs));
+        assert_eq!(lockouts, vec![None, None, secs(30), secs(60), secs(120), secs(240), secs(300), secs(300)]);
+        assert_eq!(counter.locked_out("alice", start + Duration::from_secs(7)), secs(300));
+        assert_eq!(counter.locked_out("alice", start + Duration::from_secs(307)), None);
+        assert_eq!(counter.locked_out("bob", start), None);
+    }
+
+    #[test]
+    fn doublings_do_not_overflow() {
+        let mut counter = FailureCounter::new(1);
+        let start = Instant::now();
+        counter.failures.insert("alice", Failures {
+            count: u32::MAX - 1,
+            last_failure: start,
+            locked_until: None,
+        });
+        assert_eq!(counter.record_failure("alice", start, BASE, MAX), Some(MAX));
+    }
+
+    #[test]
+    fn count_resets_after_the_longest_lockout() {
+        let mut counter = FailureCounter::new(2);
+        let start = Instant::now();
+        assert_eq!(counter.record_failure("alice", start, BASE, MAX), None);
+        assert_eq!(counter.record_failure("alice", start + Duration::from_secs(10), BASE, MAX), Some(BASE));
+        // Just short of the longest lockout since the last failure, the count goes on.
+        let later = start + Duration::from_secs(10) + MAX - Duration::from_secs(1);
+        assert_eq!(counter.record_failure("alice", later, BASE, MAX), Some(BASE * 2));
+        // A full longest lockout without failures starts over.
+        let much_later = later + MAX;
+        assert_eq!(counter.record_failure("alice", much_later, BASE, MAX), None);
+        assert_eq!(counter.failures["alice"].count, 1);
+    }
+
+    #[test]
+    fn sweep_keeps_locked_and_recent_keys() {
+        let mut counter = FailureCounter::new(2);
+        let start = Instant::now();
+        counter.record_failure("alice", start, BASE, MAX);
+        counter.record_failure("alice", start, BASE, MAX);
+        counter.record_failure("bob", start + Duration::from_secs(100), BASE, MAX);
+        counter.sweep(start + MAX, MAX);
+        assert!(!counter.failures.contains_key("alice"));
+        assert!(counter.failures.contains_key("bob"));
+    }
+}
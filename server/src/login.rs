@@ -0,0 +1,77 @@
+use std::io;
+use std::net::IpAddr;
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
+use std::thread;
+use log::warn;
+use crate::credentials::CredentialStore;
+use crate::state::ConnectionId;
+
+// A login that passed the lockout check and waits for its password to be verified.
+pub struct PendingLogin {
+    pub connection_id: ConnectionId,
+    pub client_id: usize,
+    pub address: IpAddr,
+    pub username: String,
+    password: String,
+    credentials: Arc<dyn CredentialStore>,
+}
+
+impl PendingLogin {
+    pub fn new(connection_id: ConnectionId, client_id: usize, address: IpAddr, username: String, password: String, credentials: Arc<dyn CredentialStore>) -> Self {
+        PendingLogin {
+            connection_id,
+            client_id,
+            address,
+            username,
+            password,
+            credentials,
+        }
+    }
+
+    pub fn verify(self) -> VerifiedLogin {
+        let verified = self.credentials.verify(&self.username, &self.password);
+        VerifiedLogin {
+            connection_id: self.connection_id,
+            client_id: self.client_id,
+            address: self.address,
+            username: self.username,
+            verified,
+        }
+    }
+}
+
+pub struct VerifiedLogin {
+    pub connection_id: ConnectionId,
+    pub client_id: usize,
+    pub address: IpAddr,
+    pub username: String,
+    pub verified: bool,
+}
+
+// Checks passwords on a thread of its own. Hashing is slow on purpose and would hold up every other
+// connection on the event loop, results are handed to the callback in the order logins were submitted.
+pub struct LoginWorker {
+    sender: Sender<PendingLogin>,
+}
+
+impl LoginWorker {
+    // The thread ends once the worker is dropped and everything submitted has been verified.
+    pub fn spawn(mut deliver: impl FnMut(VerifiedLogin) + Send + 'static) -> io::Result<Self> {
+        let (sender, receiver) = mpsc::channel::<PendingLogin>();
+        thread::Builder::new().name("login".to_string()).spawn(move || {
+            for login in receiver {
+                deliver(login.verify());
+            }
+        })?;
+        Ok(LoginWorker {
+            sender,
+        })
+    }
+
+    pub fn submit(&self, login: PendingLogin) {
+        if self.sender.send(login).is_err() {
+            warn!("The login thread is gone, a login was not checked.");
+        }
+    }
+}
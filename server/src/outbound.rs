@@ -0,0 +1,99 @@
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use serde::{Deserialize, Serialize};
+use crate::state::Outgoing;
+
+pub static OUTBOUND_DROPS: AtomicU64 = AtomicU64::new(0);
+pub static SLOW_CONSUMER_DISCONNECTS: AtomicU64 = AtomicU64::new(0);
+
+// What happens to a packet for a client whose outbound queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SlowConsumerPolicy {
+    // Make room by dropping the oldest packet that has not been started on.
+    DropOldest,
+    DropNewest,
+    // Drop everything queued and tell the client it was too slow.
+    Disconnect,
+}
+
+impl FromStr for SlowConsumerPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().replace('-', "_").as_str() {
+            "drop_oldest" => Ok(SlowConsumerPolicy::DropOldest),
+            "drop_newest" => Ok(SlowConsumerPolicy::DropNewest),
+            "disconnect" => Ok(SlowConsumerPolicy::Disconnect),
+            _ => Err(format!("unknown slow consumer policy '{}', expected drop_oldest, drop_newest or disconnect", value)),
+        }
+    }
+}
+
+// The queue filled up under the disconnect policy, the transport has to tell the state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueFull;
+
+// Commands for one connection that the socket has not taken yet. Only packets count against the limit
+// and only packets are ever dropped, version changes and the close always go through in order.
+pub struct OutboundQueue {
+    commands: VecDeque<Outgoing>,
+    queued_packets: usize,
+    limit: usize,
+    policy: SlowConsumerPolicy,
+}
+
+impl OutboundQueue {
+    pub fn new(limit: usize, policy: SlowConsumerPolicy) -> Self {
+        OutboundQueue {
+            commands: VecDeque::new(),
+            queued_packets: 0,
+            limit,
+            policy,
+        }
+    }
+
+    pub fn push(&mut self, outgoing: Outgoing) -> Result<(), QueueFull> {
+        if matches!(outgoing, Outgoing::Send(_)) && self.queued_packets >= self.limit {
+            match self.policy {
+                SlowConsumerPolicy::DropOldest => {
+                    if let Some(index) = self.commands.iter().position(|command| matches!(command, Outgoing::Send(_))) {
+                        self.commands.remove(index);
+                        self.queued_packets -= 1;
+                    }
+                    OUTBOUND_DROPS.fetch_add(1, Ordering::SeqCst);
+                }
+                SlowConsumerPolicy::DropNewest => {
+                    OUTBOUND_DROPS.fetch_add(1, Ordering::SeqCst);
+                    return Ok(());
+                }
+                SlowConsumerPolicy::Disconnect => {
+                    let dropped = self.queued_packets + 1;
+                    self.commands.retain(|command| !matches!(command, Outgoing::Send(_)));
+                    self.queued_packets = 0;
+                    OUTBOUND_DROPS.fetch_add(dropped as u64, Ordering::SeqCst);
+                    return Err(QueueFull);
+                }
+            }
+        }
+        if matches!(outgoing, Outgoing::Send(_)) {
+            self.queued_packets += 1;
+        }
+        self.commands.push_back(outgoing);
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> Option<Outgoing> {
+        let outgoing = self.commands.pop_front()?;
+        if matches!(outgoing, Outgoing::Send(_)) {
+            self.queued_packets -= 1;
+        }
+        Some(outgoing)
+    }
+
+    #[cfg(not(feature = "async"))]
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+}
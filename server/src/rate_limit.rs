@@ -0,0 +1,348 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use config::{DataType, Packet};
+use crate::state::ConnectionId;
+
+pub static RATE_LIMIT_DROPS: AtomicU64 = AtomicU64::new(0);
+pub static RATE_LIMIT_DELAYS: AtomicU64 = AtomicU64::new(0);
+pub static RATE_LIMIT_DISCONNECTS: AtomicU64 = AtomicU64::new(0);
+
+// Names accepted in limits, only packets a client sends are worth limiting.
+const PACKET_NAMES: &[(&str, DataType)] = &[
+    ("auth_request", DataType::AuthRequest),
+    ("ping", DataType::Ping),
+    ("disconnect", DataType::Disconnect),
+    ("game_data", DataType::GameData),
+    ("hello", DataType::Hello),
+    ("resume_session", DataType::ResumeSession),
+    ("heartbeat", DataType::Heartbeat),
+    ("chat_message", DataType::ChatMessage),
+];
+
+// What the server does with a packet that goes over a limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitAction {
+    Drop,
+    // Hold the packet, and everything after it from the same connection, until the buckets refill.
+    Delay,
+    Disconnect,
+}
+
+impl FromStr for RateLimitAction {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "drop" => Ok(RateLimitAction::Drop),
+            "delay" => Ok(RateLimitAction::Delay),
+            "disconnect" => Ok(RateLimitAction::Disconnect),
+            _ => Err(format!("unknown rate limit action '{}', expected drop, delay or disconnect", value)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RateLimitTarget {
+    All,
+    Packet(DataType),
+}
+
+impl RateLimitTarget {
+    // Sequenced packets count as the packet they carry.
+    fn matches(&self, packet: &Packet) -> bool {
+        match (self, packet) {
+            (RateLimitTarget::All, _) => true,
+            (RateLimitTarget::Packet(data_type), Packet::Sequenced { packet, .. }) => packet.data_type() == *data_type,
+            (RateLimitTarget::Packet(data_type), packet) => packet.data_type() == *data_type,
+        }
+    }
+}
+
+impl FromStr for RateLimitTarget {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim().to_ascii_lowercase();
+        if value == "all" {
+            return Ok(RateLimitTarget::All);
+        }
+        PACKET_NAMES
+            .iter()
+            .find(|(name, _)| *name == value)
+            .map(|(_, data_type)| RateLimitTarget::Packet(*data_type))
+            .ok_or_else(|| {
+                let names: Vec<&str> = PACKET_NAMES.iter().map(|(name, _)| *name).collect();
+                format!("unknown packet '{}', expected all, {}", value, names.join(", "))
+            })
+    }
+}
+
+impl fmt::Display for RateLimitTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RateLimitTarget::All => write!(f, "all"),
+            RateLimitTarget::Packet(data_type) => {
+                let name = PACKET_NAMES.iter().find(|(_, known)| known == data_type).map_or("unknown", |(name, _)| *name);
+                write!(f, "{}", name)
+            }
+        }
+    }
+}
+
+// Written as packet=per_second/burst, so ping=10/20 allows bursts of 20 pings and 10 a second after that.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub target: RateLimitTarget,
+    pub per_second: u32,
+    pub burst: u32,
+}
+
+impl FromStr for RateLimit {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid rate limit '{}', expected packet=per_second/burst", value.trim());
+        let (target, rates) = value.split_once('=').ok_or_else(invalid)?;
+        let (per_second, burst) = rates.split_once('/').ok_or_else(invalid)?;
+        let per_second: u32 = per_second.trim().parse().map_err(|_| invalid())?;
+        let burst: u32 = burst.trim().parse().map_err(|_| invalid())?;
+        if per_second == 0 || burst == 0 {
+            return Err(format!("rate limit '{}' has to allow at least one packet", value.trim()));
+        }
+        Ok(RateLimit {
+            target: target.parse()?,
+            per_second,
+            burst,
+        })
+    }
+}
+
+impl fmt::Display for RateLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}/{}", self.target, self.per_second, self.burst)
+    }
+}
+
+impl Serialize for RateLimit {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for RateLimit {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        value.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimitStats {
+    pub dropped: u64,
+    pub delayed: u64,
+    pub disconnected: u64,
+}
+
+impl RateLimitStats {
+    pub fn current() -> Self {
+        RateLimitStats {
+            dropped: RATE_LIMIT_DROPS.load(Ordering::SeqCst),
+            delayed: RATE_LIMIT_DELAYS.load(Ordering::SeqCst),
+            disconnected: RATE_LIMIT_DISCONNECTS.load(Ordering::SeqCst),
+        }
+    }
+}
+
+impl fmt::Display for RateLimitStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} dropped, {} delayed, {} disconnected", self.dropped, self.delayed, self.disconnected)
+    }
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    fn full(limit: &RateLimit, now: Instant) -> Self {
+        TokenBucket {
+            tokens: limit.burst as f64,
+            updated_at: now,
+        }
+    }
+
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_second as f64).min(limit.burst as f64);
+        self.updated_at = now;
+    }
+
+    // How long until a whole token is available, zero when one is.
+    fn wait(&self, limit: &RateLimit) -> Duration {
+        if self.tokens >= 1.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64((1.0 - self.tokens) / limit.per_second as f64)
+    }
+
+    fn is_full(&self, limit: &RateLimit) -> bool {
+        self.tokens >= limit.burst as f64
+    }
+}
+
+// A token bucket per connection and limit, and one per source address and limit shared by all its connections.
+pub struct RateLimiter {
+    connection_limits: Vec<RateLimit>,
+    ip_limits: Vec<RateLimit>,
+    connection_buckets: HashMap<(ConnectionId, RateLimitTarget), TokenBucket>,
+    ip_buckets: HashMap<(IpAddr, RateLimitTarget), TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(connection_limits: Vec<RateLimit>, ip_limits: Vec<RateLimit>) -> Self {
+        RateLimiter {
+            connection_limits,
+            ip_limits,
+            connection_buckets: HashMap::new(),
+            ip_buckets: HashMap::new(),
+        }
+    }
+
+    // Takes a token from every bucket the packet counts against, or none when one of them is empty.
+    // Err holds how long until every one of them has a token again.
+    pub fn check(&mut self, connection_id: ConnectionId, address: IpAddr, packet: &Packet) -> Result<(), Duration> {
+        self.check_at(connection_id, address, packet, Instant::now())
+    }
+
+    fn check_at(&mut self, connection_id: ConnectionId, address: IpAddr, packet: &Packet, now: Instant) -> Result<(), Duration> {
+        let address = address.to_canonical();
+        let mut wait = Duration::ZERO;
+        for limit in self.connection_limits.iter().filter(|limit| limit.target.matches(packet)) {
+            let bucket = self.connection_buckets.entry((connection_id, limit.target)).or_insert_with(|| TokenBucket::full(limit, now));
+            bucket.refill(limit, now);
+            wait = wait.max(bucket.wait(limit));
+        }
+        for limit in self.ip_limits.iter().filter(|limit| limit.target.matches(packet)) {
+            let bucket = self.ip_buckets.entry((address, limit.target)).or_insert_with(|| TokenBucket::full(limit, now));
+            bucket.refill(limit, now);
+            wait = wait.max(bucket.wait(limit));
+        }
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+        for limit in self.connection_limits.iter().filter(|limit| limit.target.matches(packet)) {
+            if let Some(bucket) = self.connection_buckets.get_mut(&(connection_id, limit.target)) {
+                bucket.tokens -= 1.0;
+            }
+        }
+        for limit in self.ip_limits.iter().filter(|limit| limit.target.matches(packet)) {
+            if let Some(bucket) = self.ip_buckets.get_mut(&(address, limit.target)) {
+                bucket.tokens -= 1.0;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn connection_closed(&mut self, connection_id: ConnectionId) {
+        self.connection_buckets.retain(|(bucket_connection_id, _), _| *bucket_connection_id != connection_id);
+    }
+
+    // Full buckets are forgotten, a new one starts full anyway.
+    pub fn sweep(&mut self) {
+        let now = Instant::now();
+        let ip_limits = &self.ip_limits;
+        self.ip_buckets.retain(|(_, target), bucket| {
+            let Some(limit) = ip_limits.iter().find(|limit| limit.target == *target) else {
+                return false;
+            };
+            bucket.refill(limit, now);
+            !bucket.is_full(limit)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(value: &str) -> RateLimit {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn bucket_refills_up_to_the_burst() {
+        let limit = limit("all=10/5");
+        let start = Instant::now();
+        let mut bucket = TokenBucket::full(&limit, start);
+        bucket.tokens = 0.0;
+        bucket.refill(&limit, start + Duration::from_millis(250));
+        assert!((bucket.tokens - 2.5).abs() < 1e-9);
+        bucket.refill(&limit, start + Duration::from_secs(60));
+        assert!(bucket.is_full(&limit));
+        assert_eq!(bucket.tokens, 5.0);
+        // Time going backwards adds nothing.
+        bucket.tokens = 1.0;
+        bucket.refill(&limit, start);
+        assert_eq!(bucket.tokens, 1.0);
+    }
+
+    #[test]
+    fn wait_is_the_time_until_a_whole_token() {
+        let limit = limit("all=4/2");
+        let mut bucket = TokenBucket::full(&limit, Instant::now());
+        assert_eq!(bucket.wait(&limit), Duration::ZERO);
+        bucket.tokens = 1.0;
+        assert_eq!(bucket.wait(&limit), Duration::ZERO);
+        bucket.tokens = 0.0;
+        assert_eq!(bucket.wait(&limit), Duration::from_millis(250));
+        bucket.tokens = 0.5;
+        assert_eq!(bucket.wait(&limit), Duration::from_millis(125));
+    }
+
+    #[test]
+    fn check_spends_the_burst_then_waits_for_refill() {
+        let mut limiter = RateLimiter::new(vec![limit("ping=2/2")], Vec::new());
+        let address: IpAddr = "127.0.0.1".parse().unwrap();
+        let start = Instant::now();
+        assert_eq!(limiter.check_at(1, address, &Packet::Ping, start), Ok(()));
+        assert_eq!(limiter.check_at(1, address, &Packet::Ping, start), Ok(()));
+        assert_eq!(limiter.check_at(1, address, &Packet::Ping, start), Err(Duration::from_millis(500)));
+        assert_eq!(limiter.check_at(1, address, &Packet::Ping, start + Duration::from_millis(200)), Err(Duration::from_millis(300)));
+        assert_eq!(limiter.check_at(1, address, &Packet::Ping, start + Duration::from_millis(500)), Ok(()));
+        // Other connections and other packets have buckets of their own.
+        assert_eq!(limiter.check_at(2, address, &Packet::Ping, start), Ok(()));
+        assert_eq!(limiter.check_at(1, address, &Packet::Heartbeat, start), Ok(()));
+    }
+
+    #[test]
+    fn check_takes_from_every_bucket_or_from_none() {
+        let mut limiter = RateLimiter::new(vec![limit("all=1/3")], vec![limit("ping=2/1")]);
+        let address: IpAddr = "10.0.0.1".parse().unwrap();
+        let start = Instant::now();
+        assert_eq!(limiter.check_at(1, address, &Packet::Ping, start), Ok(()));
+        // The address bucket is empty, the connection bucket must keep its tokens.
+        assert_eq!(limiter.check_at(1, address, &Packet::Ping, start), Err(Duration::from_millis(500)));
+        assert_eq!(limiter.check_at(1, address, &Packet::Ping, start), Err(Duration::from_millis(500)));
+        assert_eq!(limiter.connection_buckets[&(1, RateLimitTarget::All)].tokens, 2.0);
+        assert_eq!(limiter.check_at(1, address, &Packet::Heartbeat, start), Ok(()));
+        assert_eq!(limiter.check_at(1, address, &Packet::Heartbeat, start), Ok(()));
+        // Now the connection bucket is the one that holds the packet back.
+        assert_eq!(limiter.check_at(1, address, &Packet::Ping, start + Duration::from_millis(500)), Err(Duration::from_millis(500)));
+        assert_eq!(limiter.ip_buckets[&(address, RateLimitTarget::Packet(DataType::Ping))].tokens, 1.0);
+    }
+
+    #[test]
+    fn mapped_addresses_share_the_ipv4_bucket() {
+        let mut limiter = RateLimiter::new(Vec::new(), vec![limit("all=1/1")]);
+        let start = Instant::now();
+        assert_eq!(limiter.check_at(1, "10.0.0.1".parse().unwrap(), &Packet::Ping, start), Ok(()));
+        assert!(limiter.check_at(2, "::ffff:10.0.0.1".parse().unwrap(), &Packet::Ping, start).is_err());
+    }
+}
@@ -0,0 +1,201 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Instant;
+use log::debug;
+use rand::rngs::OsRng;
+use rand::Rng;
+use config::{Packet, CHALLENGE_SIZE, FIXED_LAYOUT_VERSION};
+use crate::state::ConnectionId;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientState {
+    // Connected, not logged in yet or logged out again.
+    Accepted,
+    Authenticated,
+    // The server is closing the connection once pending writes are out.
+    Closing,
+    // No connection, the record only stays while its session can be resumed.
+    Closed,
+}
+
+pub struct Client {
+    pub token: Option<String>,
+    pub id: usize,
+    pub username: Option<String>,
+    pub address: SocketAddr,
+    pub connection: Option<ConnectionId>,
+    // Direct messages that arrived while the connection was lost, delivered when the session comes back.
+    pub message_buffer: Vec<Packet>,
+    pub state: ClientState,
+    pub version: Option<u8>,
+    pub checksum_failures: u64,
+    // Sent in HelloAck, the transport binds encrypted frames of this connection to it.
+    pub challenge: [u8; CHALLENGE_SIZE],
+    // The epoch of the sequenced packets this client sends, once one has arrived.
+    pub delivery_epoch: Option<u32>,
+    // When anything last arrived from the client, including frames that failed to decode.
+    pub last_seen: Instant,
+}
+
+impl Client {
+    fn new(id: usize, connection: ConnectionId, address: SocketAddr) -> Self {
+        Client {
+            token: None,
+            id,
+            username: None,
+            address,
+            connection: Some(connection),
+            message_buffer: Vec::new(),
+            state: ClientState::Accepted,
+            version: None,
+            checksum_failures: 0,
+            challenge: OsRng.gen(),
+            delivery_epoch: None,
+            last_seen: Instant::now(),
+        }
+    }
+
+    pub fn is_authenticated(&self) -> bool {
+        self.state == ClientState::Authenticated
+    }
+
+    // Clients on the fixed layout only understand the packets version 1 had.
+    pub fn can_receive(&self, packet: &Packet) -> bool {
+        self.version != Some(FIXED_LAYOUT_VERSION) || packet.encode_fixed().is_ok()
+    }
+
+    pub fn transition(&mut self, state: ClientState) {
+        if self.state != state {
+            debug!("Client {}: {:?} -> {:?}", self.id, self.state, state);
+            self.state = state;
+        }
+    }
+}
+
+// Every client record by id, with the connection each live one is on and the last client to log in under each name.
+pub struct ClientRegistry {
+    clients: HashMap<usize, Client>,
+    connections: HashMap<ConnectionId, usize>,
+    usernames: HashMap<String, usize>,
+    max_connections: usize,
+    next_id: usize,
+}
+
+impl ClientRegistry {
+    pub fn new(max_connections: usize) -> Self {
+        ClientRegistry {
+            clients: HashMap::new(),
+            connections: HashMap::new(),
+            usernames: HashMap::new(),
+            max_connections,
+            next_id: 1,
+        }
+    }
+
+    pub fn max_connections(&self) -> usize {
+        self.max_connections
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.connections.len() >= self.max_connections
+    }
+
+    // Returns the new client.
+    pub fn open(&mut self, connection_id: ConnectionId, address: SocketAddr) -> &Client {
+        let client_id = self.next_id;
+        self.next_id += 1;
+        self.connections.insert(connection_id, client_id);
+        self.clients.entry(client_id).or_insert(Client::new(client_id, connection_id, address))
+    }
+
+    pub fn get(&self, client_id: usize) -> Option<&Client> {
+        self.clients.get(&client_id)
+    }
+
+    pub fn get_mut(&mut self, client_id: usize) -> Option<&mut Client> {
+        self.clients.get_mut(&client_id)
+    }
+
+    pub fn contains(&self, client_id: usize) -> bool {
+        self.clients.contains_key(&client_id)
+    }
+
+    // The client a connection belongs to, until that connection is closed.
+    pub fn client_id(&self, connection_id: ConnectionId) -> Option<usize> {
+        self.connections.get(&connection_id).copied()
+    }
+
+    // Only clients that are logged in right now are found.
+    pub fn by_username(&self, username: &str) -> Option<&Client> {
+        let client_id = self.usernames.get(username)?;
+        self.clients.get(client_id).filter(|client| client.is_authenticated())
+    }
+
+    // The record a lost session waits on, while no client is logged in under the name.
+    pub fn resumable_by_username_mut(&mut self, username: &str) -> Option<&mut Client> {
+        let client_id = self.usernames.get(username)?;
+        self.clients.get_mut(client_id).filter(|client| client.state == ClientState::Closed && client.token.is_some())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Client> {
+        self.clients.values()
+    }
+
+    pub fn connected(&self) -> impl Iterator<Item = &Client> {
+        self.clients.values().filter(|client| client.connection.is_some())
+    }
+
+    pub fn authenticate(&mut self, client_id: usize, username: String, token: String) {
+        let Some(client) = self.clients.get_mut(&client_id) else {
+            return;
+        };
+        client.token = Some(token);
+        client.transition(ClientState::Authenticated);
+        self.usernames.insert(username.clone(), client_id);
+        client.username = Some(username);
+    }
+
+    // Puts a connection on an existing record. Returns the connection the record had before.
+    pub fn attach(&mut self, client_id: usize, connection_id: ConnectionId) -> Option<ConnectionId> {
+        let client = self.clients.get_mut(&client_id)?;
+        self.connections.insert(connection_id, client_id);
+        client.transition(ClientState::Accepted);
+        client.last_seen = Instant::now();
+        client.connection.replace(connection_id)
+    }
+
+    // Forgets the connection. Returns the client when it was that client's current connection,
+    // a connection replaced by a resume closes without touching the record.
+    pub fn connection_closed(&mut self, connection_id: ConnectionId) -> Option<&mut Client> {
+        let client_id = self.connections.remove(&connection_id)?;
+        let client = self.clients.get_mut(&client_id)?;
+        if client.connection != Some(connection_id) {
+            return None;
+        }
+        client.connection = None;
+        client.transition(ClientState::Closed);
+        Some(client)
+    }
+
+    pub fn remove(&mut self, client_id: usize) -> Option<Client> {
+        let client = self.clients.remove(&client_id)?;
+        if let Some(connection_id) = client.connection {
+            self.connections.remove(&connection_id);
+        }
+        if let Some(username) = &client.username {
+            if self.usernames.get(username) == Some(&client_id) {
+                self.usernames.remove(username);
+            }
+        }
+        Some(client)
+    }
+
+    // Removes every client the predicate rejects. Returns how many were removed.
+    pub fn retain(&mut self, mut keep: impl FnMut(&Client) -> bool) -> usize {
+        let removed_ids: Vec<usize> = self.clients.values().filter(|client| !keep(client)).map(|client| client.id).collect();
+        for &client_id in &removed_ids {
+            self.remove(client_id);
+        }
+        removed_ids.len()
+    }
+}
@@ -0,0 +1,321 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, ErrorKind};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use log::{info, warn};
+use mio::net::TcpListener;
+use mio::{Events, Interest, Poll, Token, Waker};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook_mio::v1_0::Signals;
+use config::{EncryptionKey, FrameDecoder, FrameEncoder};
+use crate::checksum::{log_decoder_losses, ChecksumPolicy};
+use crate::connection::{Connection, ReadStatus};
+use crate::login::{LoginWorker, VerifiedLogin};
+use crate::outbound::{OutboundQueue, SlowConsumerPolicy};
+use crate::settings::Settings;
+use crate::state::ServerState;
+
+const LISTENER: Token = Token(0);
+const SIGNALS: Token = Token(1);
+// Woken by the login thread when a password check is done.
+const LOGINS: Token = Token(2);
+const POLL_EVENTS_CAPACITY: usize = 1024;
+// Chunks read from one connection per readiness event, a busy peer then waits its turn behind the others.
+const MAX_READS_PER_EVENT: usize = 16;
+
+// Single threaded reactor. Every socket is non-blocking and registered with one poll instance,
+// the thread sleeps in poll until a socket is ready or a timer is due: the next heartbeat, session sweep
+// or delayed packet.
+pub struct Server {
+    poll: Poll,
+    // Dropped when shutdown starts so nobody new gets in.
+    listener: Option<TcpListener>,
+    signals: Signals,
+    connections: HashMap<Token, Connection>,
+    // Connections that still had data to read when their budget ran out, mio will not report them again.
+    unfinished_reads: VecDeque<Token>,
+    state: ServerState,
+    login_worker: LoginWorker,
+    verified_logins: Receiver<VerifiedLogin>,
+    key: Option<EncryptionKey>,
+    max_payload_size: usize,
+    outbound_queue_limit: usize,
+    slow_consumer_policy: SlowConsumerPolicy,
+    sweep_interval: Duration,
+    heartbeat_interval: Duration,
+    shutdown_timeout: Duration,
+    shutdown_deadline: Option<Instant>,
+    next_token: usize,
+}
+
+impl Server {
+    pub fn bind(settings: &Settings, state: ServerState) -> io::Result<Self> {
+        let poll = Poll::new()?;
+        let mut listener = TcpListener::bind(settings.bind_address)?;
+        poll.registry().register(&mut listener, LISTENER, Interest::READABLE)?;
+        let mut signals = Signals::new([SIGINT, SIGTERM])?;
+        poll.registry().register(&mut signals, SIGNALS, Interest::READABLE)?;
+        let waker = Arc::new(Waker::new(poll.registry(), LOGINS)?);
+        let (verified_sender, verified_logins) = mpsc::channel();
+        let login_worker = LoginWorker::spawn(move |login| {
+            if verified_sender.send(login).is_ok() {
+                let _ = waker.wake();
+            }
+        })?;
+        Ok(Server {
+            poll,
+            listener: Some(listener),
+            signals,
+            connections: HashMap::new(),
+            unfinished_reads: VecDeque::new(),
+            state,
+            login_worker,
+            verified_logins,
+            key: EncryptionKey::from_env(),
+            max_payload_size: settings.max_payload_size,
+            outbound_queue_limit: settings.outbound_queue_limit,
+            slow_consumer_policy: settings.slow_consumer_policy,
+            sweep_interval: settings.session_sweep_interval(),
+            heartbeat_interval: settings.heartbeat_interval(),
+            shutdown_timeout: settings.shutdown_timeout(),
+            shutdown_deadline: None,
+            next_token: LOGINS.0 + 1,
+        })
+    }
+
+    // Returns after SIGINT or SIGTERM once every client got the shutdown notice or the deadline passed.
+    pub fn run(&mut self) -> io::Result<()> {
+        let mut poll_events = Events::with_capacity(POLL_EVENTS_CAPACITY);
+        let mut next_sweep = Instant::now() + self.sweep_interval;
+        let mut next_heartbeat = Instant::now() + self.heartbeat_interval;
+        loop {
+            let mut next_timer = next_sweep.min(next_heartbeat);
+            if let Some(release_at) = self.state.next_release() {
+                next_timer = next_timer.min(release_at);
+            }
+            let wake_up = self.shutdown_deadline.map_or(next_timer, |deadline| deadline.min(next_timer));
+            let mut timeout = wake_up.saturating_duration_since(Instant::now());
+            if !self.unfinished_reads.is_empty() {
+                timeout = Duration::ZERO;
+            }
+            if let Err(e) = self.poll.poll(&mut poll_events, Some(timeout)) {
+                if e.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+            // Only the connections left over from before, whatever this round leaves over waits for the next one.
+            let unfinished_reads = std::mem::take(&mut self.unfinished_reads);
+            for poll_event in poll_events.iter() {
+                match poll_event.token() {
+                    LISTENER => self.accept_connections(),
+                    LOGINS => self.finish_logins(),
+                    SIGNALS => {
+                        if self.signals.pending().next().is_none() {
+                            continue;
+                        }
+                        if self.shutdown_deadline.is_some() {
+                            warn!("Signalled again, stopping without waiting for {} connections.", self.connections.len());
+                            return Ok(());
+                        }
+                        self.begin_shutdown();
+                    }
+                    token => {
+                        if poll_event.is_readable() || poll_event.is_read_closed() || poll_event.is_error() {
+                            self.read_connection(token);
+                        }
+                        if poll_event.is_writable() {
+                            self.flush_connection(token);
+                        }
+                    }
+                }
+            }
+            for token in unfinished_reads {
+                self.read_connection(token);
+            }
+            if let Some(deadline) = self.shutdown_deadline {
+                if self.connections.is_empty() {
+                    info!("Server stopped.");
+                    return Ok(());
+                }
+                if Instant::now() >= deadline {
+                    warn!("Shutdown deadline passed, dropping {} connections.", self.connections.len());
+                    return Ok(());
+                }
+            }
+            if self.state.next_release().is_some_and(|release_at| Instant::now() >= release_at) {
+                self.state.release_delayed();
+                self.apply_outgoing();
+            }
+            if Instant::now() >= next_heartbeat {
+                self.state.check_idle();
+                self.apply_outgoing();
+                next_heartbeat = Instant::now() + self.heartbeat_interval;
+            }
+            if Instant::now() >= next_sweep {
+                self.state.sweep();
+                next_sweep = Instant::now() + self.sweep_interval;
+            }
+        }
+    }
+
+    fn accept_connections(&mut self) {
+        loop {
+            let Some(listener) = &self.listener else {
+                return;
+            };
+            let (mut stream, address) = match listener.accept() {
+                Ok(accepted) => accepted,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    warn!("Connection failed! {}", e);
+                    return;
+                }
+            };
+            if let Err(denial) = self.state.check_access(address.ip()) {
+                warn!("Rejected connection from {}: {}", address, denial);
+                drop(stream);
+                continue;
+            }
+            let token = Token(self.next_token);
+            self.next_token += 1;
+            if let Err(e) = self.poll.registry().register(&mut stream, token, Interest::READABLE) {
+                warn!("Could not register connection from {}: {}", address, e);
+                continue;
+            }
+            info!("New connection from {}", address);
+            let resync = self.state.checksum_policy() == ChecksumPolicy::Resync;
+            let challenge = self.state.open_connection(token.0, address);
+            let key = self.key.as_ref().map(|key| key.bound_to(&challenge));
+            let decoder = FrameDecoder::with_max_payload_size(self.max_payload_size).with_key(key.clone()).with_resync(resync);
+            let encoder = FrameEncoder::new().with_key(key).with_checksum(true);
+            let outbound = OutboundQueue::new(self.outbound_queue_limit, self.slow_consumer_policy);
+            self.connections.insert(token, Connection::new(stream, decoder, encoder, outbound));
+        }
+    }
+
+    fn begin_shutdown(&mut self) {
+        if let Some(mut listener) = self.listener.take() {
+            let _ = self.poll.registry().deregister(&mut listener);
+        }
+        let notified_clients = self.state.shutdown();
+        info!("Shutting down, disconnecting {} clients.", notified_clients);
+        self.apply_outgoing();
+        self.shutdown_deadline = Some(Instant::now() + self.shutdown_timeout);
+    }
+
+    fn finish_logins(&mut self) {
+        while let Ok(login) = self.verified_logins.try_recv() {
+            self.state.login_verified(login);
+        }
+        self.apply_outgoing();
+    }
+
+    // Decodes and answers after every chunk so neither the decoder nor the outgoing queue grows with a busy peer.
+    fn read_connection(&mut self, token: Token) {
+        for _ in 0..MAX_READS_PER_EVENT {
+            let Some(connection) = self.connections.get_mut(&token) else {
+                return;
+            };
+            if connection.closing {
+                return;
+            }
+            let read_status = match connection.read_chunk() {
+                Ok(read_status) => read_status,
+                Err(e) if e.kind() == ErrorKind::ConnectionReset => {
+                    info!("Connection lost to client. {}", e);
+                    ReadStatus::Closed
+                }
+                Err(e) => {
+                    warn!("Connection {}: read failed: {}", token.0, e);
+                    ReadStatus::Closed
+                }
+            };
+            // Whatever arrived before the peer went away is still handled.
+            while let Some(frame) = connection.decoder.next_packet_with_header().transpose() {
+                self.state.handle_frame(token.0, frame);
+            }
+            self.apply_outgoing();
+            match read_status {
+                ReadStatus::Received => {}
+                ReadStatus::Drained => return,
+                ReadStatus::Closed => {
+                    info!("Connection {}: closed.", token.0);
+                    self.close_connection(token);
+                    return;
+                }
+            }
+        }
+        if !self.unfinished_reads.contains(&token) {
+            self.unfinished_reads.push_back(token);
+        }
+    }
+
+    // Hands what the state produced to the login thread and the connections and pushes out what the sockets accept.
+    // A slow consumer makes the state queue its disconnect notice, which goes out in the next round.
+    fn apply_outgoing(&mut self) {
+        for login in self.state.take_logins() {
+            self.login_worker.submit(login);
+        }
+        let mut touched_tokens = Vec::new();
+        loop {
+            let outgoing = self.state.take_outgoing();
+            if outgoing.is_empty() {
+                break;
+            }
+            for (connection_id, outgoing) in outgoing {
+                let token = Token(connection_id);
+                let Some(connection) = self.connections.get_mut(&token) else {
+                    continue;
+                };
+                if connection.queue(outgoing).is_err() {
+                    self.state.slow_consumer(connection_id);
+                }
+                touched_tokens.push(token);
+            }
+        }
+        touched_tokens.sort();
+        touched_tokens.dedup();
+        for token in touched_tokens {
+            self.flush_connection(token);
+        }
+    }
+
+    // Only asks for writable readiness while something is left over, otherwise the poll would wake up constantly.
+    fn flush_connection(&mut self, token: Token) {
+        let Some(connection) = self.connections.get_mut(&token) else {
+            return;
+        };
+        if let Err(e) = connection.flush() {
+            warn!("Connection {}: write failed: {}", token.0, e);
+            self.close_connection(token);
+            return;
+        }
+        let has_pending_writes = connection.has_pending_writes();
+        if !has_pending_writes && connection.closing {
+            self.close_connection(token);
+            return;
+        }
+        if has_pending_writes != connection.writable_registered {
+            let interest = if has_pending_writes {
+                Interest::READABLE | Interest::WRITABLE
+            } else {
+                Interest::READABLE
+            };
+            if let Err(e) = self.poll.registry().reregister(&mut connection.stream, token, interest) {
+                warn!("Connection {}: could not update poll interest: {}", token.0, e);
+            }
+            connection.writable_registered = has_pending_writes;
+        }
+    }
+
+    fn close_connection(&mut self, token: Token) {
+        let Some(mut connection) = self.connections.remove(&token) else {
+            return;
+        };
+        let _ = self.poll.registry().deregister(&mut connection.stream);
+        log_decoder_losses(token.0, &connection.decoder);
+        self.state.connection_closed(token.0);
+    }
+}
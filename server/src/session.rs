@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use rand::rngs::OsRng;
+use rand::Rng;
+use crate::settings::Settings;
+
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub username: String,
+    pub client_id: usize,
+    pub created_at: Instant,
+    pub expires_at: Instant,
+    pub detached_at: Option<Instant>,
+}
+
+impl Session {
+    pub fn is_expired(&self, now: Instant, resume_grace_period: Duration) -> bool {
+        let grace_period_over = self.detached_at.is_some_and(|detached_at| now >= detached_at + resume_grace_period);
+        now >= self.expires_at || grace_period_over
+    }
+}
+
+// Every token handed out by the server, a connection is only trusted while its token is in here.
+pub struct SessionRegistry {
+    sessions: HashMap<String, Session>,
+    ttl: Duration,
+    resume_grace_period: Duration,
+    token_length: usize,
+}
+
+impl SessionRegistry {
+    pub fn new(ttl: Duration, resume_grace_period: Duration, token_length: usize) -> Self {
+        SessionRegistry {
+            sessions: HashMap::new(),
+            ttl,
+            resume_grace_period,
+            token_length,
+        }
+    }
+
+    pub fn from_settings(settings: &Settings) -> Self {
+        SessionRegistry::new(settings.session_ttl(), settings.resume_grace_period(), settings.token_length)
+    }
+
+    pub fn create(&mut self, username: &str, client_id: usize) -> String {
+        let mut token = generate_session_token(self.token_length);
+        while self.sessions.contains_key(&token) {
+            token = generate_session_token(self.token_length);
+        }
+        let now = Instant::now();
+        self.sessions.insert(token.clone(), Session {
+            username: username.to_string(),
+            client_id,
+            created_at: now,
+            expires_at: now + self.ttl,
+            detached_at: None,
+        });
+        token
+    }
+
+    // None for unknown and expired tokens, expired ones are removed on the spot.
+    pub fn validate(&mut self, token: &str) -> Option<&Session> {
+        let expired = self.sessions.get(token)?.is_expired(Instant::now(), self.resume_grace_period);
+        if expired {
+            self.sessions.remove(token);
+            return None;
+        }
+        self.sessions.get(token)
+    }
+
+    pub fn age(&self, token: &str) -> Option<Duration> {
+        self.sessions.get(token).map(|session| session.created_at.elapsed())
+    }
+
+    // The connection went away without logging out, the session stays resumable for a while.
+    pub fn detach(&mut self, token: &str) {
+        if let Some(session) = self.sessions.get_mut(token) {
+            session.detached_at = Some(Instant::now());
+        }
+    }
+
+    pub fn resume(&mut self, token: &str) -> Option<Session> {
+        self.validate(token)?;
+        let session = self.sessions.get_mut(token)?;
+        session.detached_at = None;
+        Some(session.clone())
+    }
+
+    pub fn invalidate(&mut self, token: &str) -> Option<Session> {
+        self.sessions.remove(token)
+    }
+
+    // Returns how many sessions were dropped.
+    pub fn sweep(&mut self) -> usize {
+        let now = Instant::now();
+        let before = self.sessions.len();
+        let resume_grace_period = self.resume_grace_period;
+        self.sessions.retain(|_, session| !session.is_expired(now, resume_grace_period));
+        before - self.sessions.len()
+    }
+}
+
+fn generate_session_token(length: usize) -> String {
+    let charset: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
+                           abcdefghijklmnopqrstuvwxyz\
+                           0123456789\
+                           !@#$%^&*()_-+=<>?";
+    let token: String = (0..length)
+        .map(|_| {
+            let idx = OsRng.gen_range(0..charset.len());
+            charset[idx] as char
+        })
+        .collect();
+
+    token
+}
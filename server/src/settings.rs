@@ -0,0 +1,353 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use clap::{Args, Parser, Subcommand};
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+use config::{AUTH_RESPONSE_SIZE, DEFAULT_MAX_PAYLOAD_SIZE};
+use crate::acl::IpNetwork;
+use crate::checksum::ChecksumPolicy;
+use crate::outbound::SlowConsumerPolicy;
+use crate::rate_limit::{RateLimit, RateLimitAction};
+
+// Read when it exists and no other file is given.
+pub const DEFAULT_CONFIG_PATH: &str = "server.toml";
+// Anything shorter could be guessed, or collide with the failed login token.
+pub const MIN_TOKEN_LENGTH: usize = 16;
+
+#[derive(Debug, Parser)]
+#[command(about = "Game server for the TCP practice protocol")]
+pub struct Cli {
+    #[arg(long, env = "TCP_PRACTICE_SERVER_CONFIG", help = "TOML settings file, defaults to server.toml when it exists")]
+    pub config: Option<PathBuf>,
+    #[arg(long, help = "Print the effective settings as TOML and exit")]
+    pub print_config: bool,
+    #[command(flatten)]
+    pub overrides: Overrides,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    // The password is prompted for, or read from the first line of stdin when that is not a terminal,
+    // so it never shows up in the shell history or the process list.
+    #[command(about = "Hash the password and save the user to the credentials file")]
+    AddUser {
+        username: String,
+    },
+}
+
+// Every setting as a flag, clap falls back to the environment variable when the flag is missing.
+#[derive(Debug, Args)]
+pub struct Overrides {
+    #[arg(long, env = "TCP_PRACTICE_BIND_ADDRESS", help = "Address to listen on")]
+    bind_address: Option<SocketAddr>,
+    #[arg(long, env = "TCP_PRACTICE_ALLOW", value_delimiter = ',', help = "CIDR blocks allowed to connect, empty allows everyone not denied")]
+    allow: Option<Vec<IpNetwork>>,
+    #[arg(long, env = "TCP_PRACTICE_DENY", value_delimiter = ',', help = "CIDR blocks refused even when an allow rule matches")]
+    deny: Option<Vec<IpNetwork>>,
+    #[arg(long, env = "TCP_PRACTICE_CREDENTIALS", help = "Credentials file with one username:hash line per user")]
+    credentials: Option<PathBuf>,
+    #[arg(long, env = "TCP_PRACTICE_MAX_CONNECTIONS", help = "Connections accepted at once, more are turned away")]
+    max_connections: Option<usize>,
+    #[arg(long, env = "TCP_PRACTICE_CONNECTION_RATE_LIMITS", value_delimiter = ',', help = "Limits per connection as packet=per_second/burst, e.g. ping=10/20 or all=100/200")]
+    connection_rate_limits: Option<Vec<RateLimit>>,
+    #[arg(long, env = "TCP_PRACTICE_IP_RATE_LIMITS", value_delimiter = ',', help = "Limits shared by all connections from one address, same format")]
+    ip_rate_limits: Option<Vec<RateLimit>>,
+    #[arg(long, env = "TCP_PRACTICE_RATE_LIMIT_ACTION", help = "What to do with packets over a limit: drop, delay or disconnect")]
+    rate_limit_action: Option<RateLimitAction>,
+    #[arg(long, env = "TCP_PRACTICE_OUTBOUND_QUEUE_LIMIT", help = "Packets that may wait for a client that is not reading")]
+    outbound_queue_limit: Option<usize>,
+    #[arg(long, env = "TCP_PRACTICE_SLOW_CONSUMER_POLICY", help = "What to do when that queue is full: drop_oldest, drop_newest or disconnect")]
+    slow_consumer_policy: Option<SlowConsumerPolicy>,
+    #[arg(long, env = "TCP_PRACTICE_LOCKOUT_THRESHOLD", help = "Failed logins for one username before it is locked out")]
+    lockout_threshold: Option<u32>,
+    #[arg(long, env = "TCP_PRACTICE_ADDRESS_LOCKOUT_THRESHOLD", help = "Failed logins from one address before it is locked out")]
+    address_lockout_threshold: Option<u32>,
+    #[arg(long, env = "TCP_PRACTICE_LOCKOUT_BASE", help = "Seconds of the first lockout, doubled with every further failure")]
+    lockout_base_secs: Option<u64>,
+    #[arg(long, env = "TCP_PRACTICE_LOCKOUT_MAX", help = "Longest lockout in seconds, failures are forgotten after this long")]
+    lockout_max_secs: Option<u64>,
+    #[arg(long, env = "TCP_PRACTICE_LOG_LEVEL", help = "off, error, warn, info, debug or trace")]
+    log_level: Option<LevelFilter>,
+    #[arg(long, env = "TCP_PRACTICE_CHECKSUM_POLICY", help = "What to do after a checksum failure: drop, resync or disconnect")]
+    checksum_policy: Option<ChecksumPolicy>,
+    #[arg(long, env = "TCP_PRACTICE_SESSION_TTL", help = "Seconds a session token stays valid")]
+    session_ttl_secs: Option<u64>,
+    #[arg(long, env = "TCP_PRACTICE_SESSION_SWEEP_INTERVAL", help = "Seconds between sweeps for expired sessions")]
+    session_sweep_interval_secs: Option<u64>,
+    #[arg(long, env = "TCP_PRACTICE_RESUME_GRACE_PERIOD", help = "Seconds a session can be resumed after its connection is lost")]
+    resume_grace_period_secs: Option<u64>,
+    #[arg(long, env = "TCP_PRACTICE_HEARTBEAT_INTERVAL", help = "Seconds a client may stay quiet before the server sends it a heartbeat")]
+    heartbeat_interval_secs: Option<u64>,
+    #[arg(long, env = "TCP_PRACTICE_IDLE_TIMEOUT", help = "Seconds a client may stay quiet before it is disconnected")]
+    idle_timeout_secs: Option<u64>,
+    #[arg(long, env = "TCP_PRACTICE_SHUTDOWN_TIMEOUT", help = "Seconds to wait for clients to receive the shutdown notice")]
+    shutdown_timeout_secs: Option<u64>,
+    #[arg(long, env = "TCP_PRACTICE_TOKEN_LENGTH", help = "Length of issued session tokens")]
+    token_length: Option<usize>,
+    #[arg(long, env = "TCP_PRACTICE_MAX_PAYLOAD_SIZE", help = "Largest payload in bytes a client may send")]
+    max_payload_size: Option<usize>,
+}
+
+impl Overrides {
+    fn apply(&self, settings: &mut Settings) {
+        if let Some(bind_address) = self.bind_address {
+            settings.bind_address = bind_address;
+        }
+        if let Some(allow) = &self.allow {
+            settings.allow = allow.clone();
+        }
+        if let Some(deny) = &self.deny {
+            settings.deny = deny.clone();
+        }
+        if let Some(credentials) = &self.credentials {
+            settings.credentials = credentials.clone();
+        }
+        if let Some(max_connections) = self.max_connections {
+            settings.max_connections = max_connections;
+        }
+        if let Some(connection_rate_limits) = &self.connection_rate_limits {
+            settings.connection_rate_limits = connection_rate_limits.clone();
+        }
+        if let Some(ip_rate_limits) = &self.ip_rate_limits {
+            settings.ip_rate_limits = ip_rate_limits.clone();
+        }
+        if let Some(rate_limit_action) = self.rate_limit_action {
+            settings.rate_limit_action = rate_limit_action;
+        }
+        if let Some(outbound_queue_limit) = self.outbound_queue_limit {
+            settings.outbound_queue_limit = outbound_queue_limit;
+        }
+        if let Some(slow_consumer_policy) = self.slow_consumer_policy {
+            settings.slow_consumer_policy = slow_consumer_policy;
+        }
+        if let Some(lockout_threshold) = self.lockout_threshold {
+            settings.lockout_threshold = lockout_threshold;
+        }
+        if let Some(address_lockout_threshold) = self.address_lockout_threshold {
+            settings.address_lockout_threshold = address_lockout_threshold;
+        }
+        if let Some(lockout_base_secs) = self.lockout_base_secs {
+            settings.lockout_base_secs = lockout_base_secs;
+        }
+        if let Some(lockout_max_secs) = self.lockout_max_secs {
+            settings.lockout_max_secs = lockout_max_secs;
+        }
+        if let Some(log_level) = self.log_level {
+            settings.log_level = log_level;
+        }
+        if let Some(checksum_policy) = self.checksum_policy {
+            settings.checksum_policy = checksum_policy;
+        }
+        if let Some(session_ttl_secs) = self.session_ttl_secs {
+            settings.session_ttl_secs = session_ttl_secs;
+        }
+        if let Some(session_sweep_interval_secs) = self.session_sweep_interval_secs {
+            settings.session_sweep_interval_secs = session_sweep_interval_secs;
+        }
+        if let Some(resume_grace_period_secs) = self.resume_grace_period_secs {
+            settings.resume_grace_period_secs = resume_grace_period_secs;
+        }
+        if let Some(heartbeat_interval_secs) = self.heartbeat_interval_secs {
+            settings.heartbeat_interval_secs = heartbeat_interval_secs;
+        }
+        if let Some(idle_timeout_secs) = self.idle_timeout_secs {
+            settings.idle_timeout_secs = idle_timeout_secs;
+        }
+        if let Some(shutdown_timeout_secs) = self.shutdown_timeout_secs {
+            settings.shutdown_timeout_secs = shutdown_timeout_secs;
+        }
+        if let Some(token_length) = self.token_length {
+            settings.token_length = token_length;
+        }
+        if let Some(max_payload_size) = self.max_payload_size {
+            settings.max_payload_size = max_payload_size;
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SettingsError {
+    Read(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SettingsError::Read(path, e) => write!(f, "could not read {}: {}", path.display(), e),
+            SettingsError::Parse(path, e) => write!(f, "could not parse {}: {}", path.display(), e),
+            SettingsError::Invalid(reason) => write!(f, "invalid settings: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for SettingsError {}
+
+// Defaults, overridden by the TOML file, then by environment variables, then by flags.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub bind_address: SocketAddr,
+    pub allow: Vec<IpNetwork>,
+    pub deny: Vec<IpNetwork>,
+    pub credentials: PathBuf,
+    pub max_connections: usize,
+    pub connection_rate_limits: Vec<RateLimit>,
+    pub ip_rate_limits: Vec<RateLimit>,
+    pub rate_limit_action: RateLimitAction,
+    // Counted in packets, on top of the batch already handed to the socket.
+    pub outbound_queue_limit: usize,
+    pub slow_consumer_policy: SlowConsumerPolicy,
+    pub lockout_threshold: u32,
+    pub address_lockout_threshold: u32,
+    pub lockout_base_secs: u64,
+    pub lockout_max_secs: u64,
+    pub log_level: LevelFilter,
+    pub checksum_policy: ChecksumPolicy,
+    pub session_ttl_secs: u64,
+    pub session_sweep_interval_secs: u64,
+    // How long a session outlives its connection, a reconnect within this window can resume it.
+    pub resume_grace_period_secs: u64,
+    // Quiet clients get a heartbeat and are disconnected once nothing arrived for the idle timeout.
+    // Both are checked once per heartbeat interval, so an eviction can be up to one interval late.
+    pub heartbeat_interval_secs: u64,
+    pub idle_timeout_secs: u64,
+    // How long a shutdown waits for pending writes before the remaining connections are dropped.
+    pub shutdown_timeout_secs: u64,
+    pub token_length: usize,
+    pub max_payload_size: usize,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            bind_address: SocketAddr::from(([127, 0, 0, 1], 8080)),
+            allow: vec![
+                IpNetwork::new(Ipv4Addr::LOCALHOST.into(), 8).unwrap(),
+                IpNetwork::new(Ipv6Addr::LOCALHOST.into(), 128).unwrap(),
+            ],
+            deny: Vec::new(),
+            credentials: PathBuf::from("credentials.txt"),
+            max_connections: 1024,
+            connection_rate_limits: vec!["all=100/200".parse().unwrap(), "ping=10/20".parse().unwrap(), "chat_message=5/10".parse().unwrap()],
+            ip_rate_limits: vec!["all=500/1000".parse().unwrap()],
+            rate_limit_action: RateLimitAction::Delay,
+            outbound_queue_limit: 1024,
+            slow_consumer_policy: SlowConsumerPolicy::Disconnect,
+            lockout_threshold: 5,
+            address_lockout_threshold: 20,
+            lockout_base_secs: 30,
+            lockout_max_secs: 15 * 60,
+            log_level: LevelFilter::Info,
+            checksum_policy: ChecksumPolicy::Resync,
+            session_ttl_secs: 60 * 60,
+            session_sweep_interval_secs: 30,
+            resume_grace_period_secs: 60,
+            heartbeat_interval_secs: 10,
+            idle_timeout_secs: 30,
+            shutdown_timeout_secs: 5,
+            token_length: AUTH_RESPONSE_SIZE,
+            max_payload_size: DEFAULT_MAX_PAYLOAD_SIZE,
+        }
+    }
+}
+
+impl Settings {
+    // A file given explicitly has to exist, the default one is optional.
+    pub fn load(cli: &Cli) -> Result<Self, SettingsError> {
+        let mut settings = match &cli.config {
+            Some(path) => Settings::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => Settings::from_file(Path::new(DEFAULT_CONFIG_PATH))?,
+            None => Settings::default(),
+        };
+        cli.overrides.apply(&mut settings);
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, SettingsError> {
+        let contents = fs::read_to_string(path).map_err(|e| SettingsError::Read(path.to_path_buf(), e))?;
+        toml::from_str(&contents).map_err(|e| SettingsError::Parse(path.to_path_buf(), e))
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string_pretty(self).expect("settings always serialize")
+    }
+
+    pub fn session_ttl(&self) -> Duration {
+        Duration::from_secs(self.session_ttl_secs)
+    }
+
+    pub fn session_sweep_interval(&self) -> Duration {
+        Duration::from_secs(self.session_sweep_interval_secs)
+    }
+
+    pub fn resume_grace_period(&self) -> Duration {
+        Duration::from_secs(self.resume_grace_period_secs)
+    }
+
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_secs(self.heartbeat_interval_secs)
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_secs)
+    }
+
+    pub fn lockout_base(&self) -> Duration {
+        Duration::from_secs(self.lockout_base_secs)
+    }
+
+    pub fn lockout_max(&self) -> Duration {
+        Duration::from_secs(self.lockout_max_secs)
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout_secs)
+    }
+
+    fn validate(&self) -> Result<(), SettingsError> {
+        if !(MIN_TOKEN_LENGTH..=AUTH_RESPONSE_SIZE).contains(&self.token_length) {
+            return Err(SettingsError::Invalid(format!("token_length must be {} to {}", MIN_TOKEN_LENGTH, AUTH_RESPONSE_SIZE)));
+        }
+        if self.max_connections == 0 {
+            return Err(SettingsError::Invalid("max_connections must be at least 1".to_string()));
+        }
+        for rate_limits in [&self.connection_rate_limits, &self.ip_rate_limits] {
+            for (index, rate_limit) in rate_limits.iter().enumerate() {
+                if rate_limits[..index].iter().any(|earlier| earlier.target == rate_limit.target) {
+                    return Err(SettingsError::Invalid(format!("more than one rate limit for {}", rate_limit.target)));
+                }
+            }
+        }
+        if self.outbound_queue_limit == 0 {
+            return Err(SettingsError::Invalid("outbound_queue_limit must be at least 1".to_string()));
+        }
+        if self.lockout_threshold == 0 || self.address_lockout_threshold == 0 {
+            return Err(SettingsError::Invalid("lockout thresholds must be at least 1".to_string()));
+        }
+        if self.lockout_base_secs == 0 || self.lockout_max_secs < self.lockout_base_secs {
+            return Err(SettingsError::Invalid("lockout_base_secs must be at least 1 and no longer than lockout_max_secs".to_string()));
+        }
+        if self.session_sweep_interval_secs == 0 {
+            return Err(SettingsError::Invalid("session_sweep_interval_secs must be at least 1".to_string()));
+        }
+        if self.heartbeat_interval_secs == 0 {
+            return Err(SettingsError::Invalid("heartbeat_interval_secs must be at least 1".to_string()));
+        }
+        if self.idle_timeout_secs <= self.heartbeat_interval_secs {
+            return Err(SettingsError::Invalid("idle_timeout_secs must be longer than heartbeat_interval_secs".to_string()));
+        }
+        if self.max_payload_size == 0 {
+            return Err(SettingsError::Invalid("max_payload_size must be at least 1".to_string()));
+        }
+        Ok(())
+    }
+}
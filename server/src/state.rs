@@ -0,0 +1,779 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use log::{debug, info, warn};
+use config::{negotiate_version, AuthFailure, DisconnectReason, EncryptionKey, GameData, Packet, PacketHeader, PayloadEncoding, ProtocolError, CHALLENGE_SIZE, LENGTH_PREFIXED_VERSION, MAX_CHAT_MESSAGE_LENGTH, SUPPORTED_VERSIONS};
+use crate::acl::{AccessControl, Denial};
+use crate::checksum::{ChecksumPolicy, CHECKSUM_FAILURES};
+use crate::credentials::CredentialStore;
+use crate::delivery::{Delivery, DeliveryTracker};
+use crate::game::{GameLogic, Recipient};
+use crate::lockout::{retry_after_secs, LoginGuard};
+use crate::login::{PendingLogin, VerifiedLogin};
+use crate::outbound::{OUTBOUND_DROPS, SLOW_CONSUMER_DISCONNECTS};
+use crate::rate_limit::{RateLimitAction, RateLimitStats, RateLimiter, RATE_LIMIT_DELAYS, RATE_LIMIT_DISCONNECTS, RATE_LIMIT_DROPS};
+use crate::registry::{ClientRegistry, ClientState};
+use crate::session::{Session, SessionRegistry};
+use crate::settings::Settings;
+use crate::{Event, EventType};
+
+pub type ConnectionId = usize;
+
+// Packets a connection can have waiting under the delay action, anything beyond is dropped.
+// Sequenced packets are never dropped, the connection is closed instead and the client resends them after resuming.
+const MAX_DELAYED_PACKETS: usize = 1024;
+// Direct messages kept for a user whose connection was lost, the oldest go first.
+const MAX_BUFFERED_MESSAGES: usize = 100;
+
+// What a transport has to do with one of its connections, in the order given.
+#[derive(Debug)]
+pub enum Outgoing {
+    Send(Packet),
+    SetVersion(u8),
+    // Close once everything sent before has been written.
+    Close,
+}
+
+// Why a new connection is turned away before anything is read from it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    Denied(Denial),
+    Full(usize),
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::Denied(denial) => write!(f, "{}", denial),
+            Rejection::Full(max_connections) => write!(f, "already at {} connections", max_connections),
+        }
+    }
+}
+
+// Packets held back by the delay action, handled in order once the buckets have refilled.
+struct DelayedPackets {
+    release_at: Instant,
+    packets: VecDeque<(PacketHeader, Packet)>,
+}
+
+// Everything the server knows apart from the sockets. Transports report connections and decoded frames
+// and carry out whatever take_outgoing returns, so the blocking and the async server share this logic.
+pub struct ServerState {
+    // A resumed session moves its connection to the older record it was issued to.
+    clients: ClientRegistry,
+    closing: HashSet<ConnectionId>,
+    events: Vec<Event>,
+    outgoing: Vec<(ConnectionId, Outgoing)>,
+    game: Box<dyn GameLogic>,
+    deliveries: DeliveryTracker,
+    credentials: Arc<dyn CredentialStore>,
+    // Password checks for the transport to run off its event loop, at most one per connection.
+    pending_logins: Vec<PendingLogin>,
+    verifying: HashSet<ConnectionId>,
+    sessions: SessionRegistry,
+    login_guard: LoginGuard,
+    checksum_policy: ChecksumPolicy,
+    // With a key configured, logins and session tokens are only accepted encrypted.
+    encryption_required: bool,
+    access_control: AccessControl,
+    heartbeat_interval: Duration,
+    idle_timeout: Duration,
+    rate_limiter: RateLimiter,
+    rate_limit_action: RateLimitAction,
+    delayed: HashMap<ConnectionId, DelayedPackets>,
+    reported_rate_limit_stats: RateLimitStats,
+    reported_outbound_drops: u64,
+}
+
+impl ServerState {
+    pub fn new(game: Box<dyn GameLogic>, credentials: Box<dyn CredentialStore>, settings: &Settings) -> Self {
+        ServerState {
+            clients: ClientRegistry::new(settings.max_connections),
+            closing: HashSet::new(),
+            events: Vec::new(),
+            outgoing: Vec::new(),
+            game,
+            deliveries: DeliveryTracker::new(),
+            credentials: Arc::from(credentials),
+            pending_logins: Vec::new(),
+            verifying: HashSet::new(),
+            sessions: SessionRegistry::from_settings(settings),
+            login_guard: LoginGuard::from_settings(settings),
+            checksum_policy: settings.checksum_policy,
+            encryption_required: EncryptionKey::from_env().is_some(),
+            access_control: AccessControl::new(settings.allow.clone(), settings.deny.clone()),
+            heartbeat_interval: settings.heartbeat_interval(),
+            idle_timeout: settings.idle_timeout(),
+            rate_limiter: RateLimiter::new(settings.connection_rate_limits.clone(), settings.ip_rate_limits.clone()),
+            rate_limit_action: settings.rate_limit_action,
+            delayed: HashMap::new(),
+            reported_rate_limit_stats: RateLimitStats::current(),
+            reported_outbound_drops: OUTBOUND_DROPS.load(Ordering::SeqCst),
+        }
+    }
+
+    pub fn checksum_policy(&self) -> ChecksumPolicy {
+        self.checksum_policy
+    }
+
+    // Asked by the transports right after accept, a rejected peer is disconnected before anything is read.
+    pub fn check_access(&self, address: IpAddr) -> Result<(), Rejection> {
+        self.access_control.check(address).map_err(Rejection::Denied)?;
+        if self.clients.is_full() {
+            return Err(Rejection::Full(self.clients.max_connections()));
+        }
+        Ok(())
+    }
+
+    // Returns the challenge the transport binds the connection's encrypted frames to.
+    pub fn open_connection(&mut self, connection_id: ConnectionId, address: SocketAddr) -> [u8; CHALLENGE_SIZE] {
+        self.clients.open(connection_id, address).challenge
+    }
+
+    // Clients with a session keep their record for a resume, anonymous ones are removed.
+    pub fn connection_closed(&mut self, connection_id: ConnectionId) {
+        self.closing.remove(&connection_id);
+        self.verifying.remove(&connection_id);
+        self.delayed.remove(&connection_id);
+        self.rate_limiter.connection_closed(connection_id);
+        let Some(client) = self.clients.connection_closed(connection_id) else {
+            return;
+        };
+        match &client.token {
+            Some(session_token) => self.sessions.detach(session_token),
+            None => {
+                let client_id = client.id;
+                self.clients.remove(client_id);
+            }
+        }
+    }
+
+    pub fn handle_frame(&mut self, connection_id: ConnectionId, frame: Result<(PacketHeader, Packet), ProtocolError>) {
+        if self.closing.contains(&connection_id) {
+            return;
+        }
+        let Some(client_id) = self.clients.client_id(connection_id) else {
+            return;
+        };
+        if let Some(client) = self.clients.get_mut(client_id) {
+            client.last_seen = Instant::now();
+        }
+        match frame {
+            Ok((header, packet)) => self.admit_packet(connection_id, client_id, header, packet),
+            Err(e @ ProtocolError::ChecksumMismatch { .. }) => {
+                let total_failures = CHECKSUM_FAILURES.fetch_add(1, Ordering::SeqCst) + 1;
+                if let Some(client) = self.clients.get_mut(client_id) {
+                    client.checksum_failures += 1;
+                    warn!("Client {}: {} ({} on this connection, {} total)", client_id, e, client.checksum_failures, total_failures);
+                }
+                if self.checksum_policy == ChecksumPolicy::Disconnect {
+                    warn!("Client {}: disconnecting after checksum failure.", client_id);
+                    self.close(connection_id);
+                }
+            }
+            Err(e) => {
+                warn!("Client {}: dropped malformed frame: {}", client_id, e);
+            }
+        }
+        self.process_events();
+    }
+
+    // When the next delayed packet is due, transports call release_delayed then.
+    pub fn next_release(&self) -> Option<Instant> {
+        self.delayed.values().map(|delayed| delayed.release_at).min()
+    }
+
+    pub fn release_delayed(&mut self) {
+        let now = Instant::now();
+        let due_connections: Vec<ConnectionId> = self.delayed.iter().filter(|(_, delayed)| delayed.release_at <= now).map(|(connection_id, _)| *connection_id).collect();
+        for connection_id in due_connections {
+            self.release_connection(connection_id);
+        }
+        self.process_events();
+    }
+
+    pub fn take_outgoing(&mut self) -> Vec<(ConnectionId, Outgoing)> {
+        std::mem::take(&mut self.outgoing)
+    }
+
+    // Transports verify these away from their event loop and report back through login_verified.
+    pub fn take_logins(&mut self) -> Vec<PendingLogin> {
+        std::mem::take(&mut self.pending_logins)
+    }
+
+    // A failed check counts against the lockout even when the client is gone by now, a success
+    // only opens a session while the connection that asked is still there.
+    pub fn login_verified(&mut self, login: VerifiedLogin) {
+        self.verifying.remove(&login.connection_id);
+        let still_connected = self.clients.client_id(login.connection_id) == Some(login.client_id) && !self.closing.contains(&login.connection_id);
+        if !still_connected && login.verified {
+            debug!("Client {}: left before its login as '{}' was checked.", login.client_id, login.username);
+            return;
+        }
+        let result = self.authenticate_client(login.client_id, &login.username, login.address, login.verified);
+        if still_connected {
+            self.answer_login(login.client_id, login.username, result);
+            self.process_events();
+        }
+    }
+
+    pub fn sweep(&mut self) {
+        let expired_sessions = self.sessions.sweep();
+        if expired_sessions > 0 {
+            info!("Swept {} expired sessions.", expired_sessions);
+        }
+        let sessions = &mut self.sessions;
+        let removed_clients = self.clients.retain(|client| {
+            client.connection.is_some() || client.token.as_ref().is_some_and(|token| sessions.validate(token).is_some())
+        });
+        if removed_clients > 0 {
+            debug!("Removed {} disconnected clients.", removed_clients);
+        }
+        let live_streams = self.clients.iter()
+            .filter_map(|client| Some((client.username.clone()?, client.delivery_epoch?)))
+            .collect();
+        let forgotten_streams = self.deliveries.retain(&live_streams);
+        if forgotten_streams > 0 {
+            debug!("Forgot the sequences of {} finished sessions.", forgotten_streams);
+        }
+        self.rate_limiter.sweep();
+        self.login_guard.sweep();
+        let rate_limit_stats = RateLimitStats::current();
+        if rate_limit_stats != self.reported_rate_limit_stats {
+            info!("Rate limited packets so far: {}", rate_limit_stats);
+            self.reported_rate_limit_stats = rate_limit_stats;
+        }
+        let outbound_drops = OUTBOUND_DROPS.load(Ordering::SeqCst);
+        if outbound_drops != self.reported_outbound_drops {
+            let disconnects = SLOW_CONSUMER_DISCONNECTS.load(Ordering::SeqCst);
+            info!("Slow clients so far: {} packets dropped, {} disconnected", outbound_drops, disconnects);
+            self.reported_outbound_drops = outbound_drops;
+        }
+    }
+
+    // Called once per heartbeat interval. Clients quiet for an interval get a heartbeat to answer,
+    // clients quiet for the idle timeout are told why and disconnected, their session stays resumable.
+    pub fn check_idle(&mut self) {
+        let now = Instant::now();
+        let mut idle_ids = Vec::new();
+        let mut quiet_ids = Vec::new();
+        for client in self.clients.connected().filter(|client| client.state != ClientState::Closing) {
+            let quiet_for = now.saturating_duration_since(client.last_seen);
+            if quiet_for >= self.idle_timeout {
+                idle_ids.push(client.id);
+            } else if quiet_for >= self.heartbeat_interval && client.version == Some(LENGTH_PREFIXED_VERSION) {
+                // Heartbeats have no fixed layout, version 1 clients are only evicted.
+                quiet_ids.push(client.id);
+            }
+        }
+        for client_id in idle_ids {
+            info!("Client {}: nothing received for {:?}, disconnecting.", client_id, self.idle_timeout);
+            self.events.push(Event::new(EventType::Kick(client_id, DisconnectReason::IdleTimeout)));
+        }
+        for client_id in quiet_ids {
+            debug!("Client {}: quiet, sending a heartbeat.", client_id);
+            queue_write(&mut self.events, client_id, Packet::Heartbeat);
+        }
+        self.process_events();
+    }
+
+    // Tells every connected client the server is going away, their connections close once that is written.
+    // Returns how many clients were told.
+    pub fn shutdown(&mut self) -> usize {
+        let connected_ids: Vec<usize> = self.clients.connected().map(|client| client.id).collect();
+        for &client_id in &connected_ids {
+            self.events.push(Event::new(EventType::Kick(client_id, DisconnectReason::ServerShutdown)));
+        }
+        self.process_events();
+        connected_ids.len()
+    }
+
+    // Called by a transport when a connection's outbound queue overflowed under the disconnect policy.
+    // The queue was emptied, so the notice goes out right after whatever was already being written.
+    pub fn slow_consumer(&mut self, connection_id: ConnectionId) {
+        if self.closing.contains(&connection_id) {
+            return;
+        }
+        SLOW_CONSUMER_DISCONNECTS.fetch_add(1, Ordering::SeqCst);
+        if let Some(client_id) = self.clients.client_id(connection_id) {
+            warn!("Client {}: not reading fast enough, disconnecting.", client_id);
+        }
+        self.outgoing.push((connection_id, Outgoing::Send(Packet::Disconnect { reason: DisconnectReason::SlowConsumer })));
+        self.close(connection_id);
+    }
+
+    fn close(&mut self, connection_id: ConnectionId) {
+        if !self.closing.insert(connection_id) {
+            return;
+        }
+        self.outgoing.push((connection_id, Outgoing::Close));
+        let client = self.clients.client_id(connection_id).and_then(|client_id| self.clients.get_mut(client_id));
+        if let Some(client) = client.filter(|client| client.connection == Some(connection_id)) {
+            client.transition(ClientState::Closing);
+        }
+    }
+
+    // Applies the rate limits before a packet is handled.
+    fn admit_packet(&mut self, connection_id: ConnectionId, client_id: usize, header: PacketHeader, packet: Packet) {
+        if let Some(delayed) = self.delayed.get_mut(&connection_id) {
+            // Everything after a delayed packet waits behind it so the order stays intact.
+            if delayed.packets.len() >= MAX_DELAYED_PACKETS && matches!(packet, Packet::Sequenced { .. }) {
+                RATE_LIMIT_DISCONNECTS.fetch_add(1, Ordering::SeqCst);
+                warn!("Client {}: too many delayed packets to keep a sequenced one, disconnecting.", client_id);
+                self.events.push(Event::new(EventType::Kick(client_id, DisconnectReason::RateLimited)));
+            } else if delayed.packets.len() >= MAX_DELAYED_PACKETS {
+                RATE_LIMIT_DROPS.fetch_add(1, Ordering::SeqCst);
+                debug!("Client {}: too many delayed packets, dropped {:?}.", client_id, packet.data_type());
+            } else {
+                RATE_LIMIT_DELAYS.fetch_add(1, Ordering::SeqCst);
+                delayed.packets.push_back((header, packet));
+            }
+            return;
+        }
+        let Some(address) = self.clients.get(client_id).map(|client| client.address.ip()) else {
+            return;
+        };
+        let Err(wait) = self.rate_limiter.check(connection_id, address, &packet) else {
+            self.handle_packet(connection_id, client_id, header, packet);
+            return;
+        };
+        let action = match self.rate_limit_action {
+            // Every later sequence would wait for a dropped one forever, so sequenced packets are delayed instead.
+            RateLimitAction::Drop if matches!(packet, Packet::Sequenced { .. }) => RateLimitAction::Delay,
+            action => action,
+        };
+        match action {
+            RateLimitAction::Drop => {
+                RATE_LIMIT_DROPS.fetch_add(1, Ordering::SeqCst);
+                debug!("Client {}: {:?} over the rate limit, dropped.", client_id, packet.data_type());
+            }
+            RateLimitAction::Delay => {
+                RATE_LIMIT_DELAYS.fetch_add(1, Ordering::SeqCst);
+                debug!("Client {}: {:?} over the rate limit, delayed by {:?}.", client_id, packet.data_type(), wait);
+                self.delayed.insert(connection_id, DelayedPackets {
+                    release_at: Instant::now() + wait,
+                    packets: VecDeque::from([(header, packet)]),
+                });
+            }
+            RateLimitAction::Disconnect => {
+                RATE_LIMIT_DISCONNECTS.fetch_add(1, Ordering::SeqCst);
+                warn!("Client {}: {:?} over the rate limit, disconnecting.", client_id, packet.data_type());
+                self.events.push(Event::new(EventType::Kick(client_id, DisconnectReason::RateLimited)));
+            }
+        }
+    }
+
+    // Handles delayed packets of one connection until the buckets run dry again.
+    fn release_connection(&mut self, connection_id: ConnectionId) {
+        loop {
+            let client = self.clients.client_id(connection_id).and_then(|client_id| self.clients.get(client_id));
+            let Some((client_id, address)) = client.map(|client| (client.id, client.address.ip())) else {
+                self.delayed.remove(&connection_id);
+                return;
+            };
+            let Some(delayed) = self.delayed.get_mut(&connection_id) else {
+                return;
+            };
+            let Some((_, packet)) = delayed.packets.front() else {
+                self.delayed.remove(&connection_id);
+                return;
+            };
+            if self.closing.contains(&connection_id) {
+                self.delayed.remove(&connection_id);
+                return;
+            }
+            if let Err(wait) = self.rate_limiter.check(connection_id, address, packet) {
+                delayed.release_at = Instant::now() + wait;
+                return;
+            }
+            if let Some((header, packet)) = delayed.packets.pop_front() {
+                self.handle_packet(connection_id, client_id, header, packet);
+            }
+        }
+    }
+
+    fn handle_packet(&mut self, connection_id: ConnectionId, client_id: usize, header: PacketHeader, packet: Packet) {
+        // The fixed layout cannot be encrypted, so this also refuses version 1 logins.
+        if self.encryption_required && packet.is_sensitive() && header.encoding != PayloadEncoding::Encrypted.to_u8() {
+            warn!("Client {}: unencrypted {:?}, disconnecting.", client_id, packet.data_type());
+            self.close(connection_id);
+            return;
+        }
+        let Some(client) = self.clients.get_mut(client_id) else {
+            return;
+        };
+        if client.version.is_none() {
+            match &packet {
+                Packet::Hello { versions } => {
+                    match negotiate_version(SUPPORTED_VERSIONS, versions) {
+                        Some(agreed_version) => {
+                            info!("Client {}: agreed on protocol version {}", client_id, agreed_version);
+                            client.version = Some(agreed_version);
+                            self.outgoing.push((connection_id, Outgoing::SetVersion(agreed_version)));
+                            let challenge = client.challenge.to_vec();
+                            queue_write(&mut self.events, client_id, Packet::HelloAck { version: agreed_version, challenge });
+                        }
+                        None => {
+                            warn!("Client {}: no common protocol version in {:?}", client_id, versions);
+                            queue_write(&mut self.events, client_id, Packet::HelloReject { versions: SUPPORTED_VERSIONS.to_vec() });
+                            self.events.push(Event::new(EventType::Close(client_id)));
+                        }
+                    }
+                    return;
+                }
+                _ if SUPPORTED_VERSIONS.contains(&header.version) => {
+                    // Builds from before the handshake start with their first packet, answer in their version.
+                    info!("Client {}: no hello, using protocol version {}", client_id, header.version);
+                    client.version = Some(header.version);
+                    self.outgoing.push((connection_id, Outgoing::SetVersion(header.version)));
+                }
+                _ => {
+                    warn!("Client {}: unsupported protocol version {}", client_id, header.version);
+                    return;
+                }
+            }
+        }
+        match packet {
+            Packet::AuthRequest { username, password } => {
+                debug!("Auth request received!");
+                if self.verifying.contains(&connection_id) {
+                    warn!("Client {}: still checking the last login, ignoring this one.", client_id);
+                    return;
+                }
+                self.end_session(client_id);
+                let Some(address) = self.clients.get(client_id).map(|client| client.address.ip()) else {
+                    return;
+                };
+                if let Some(lockout) = self.login_guard.locked_out(&username, address) {
+                    self.answer_login(client_id, username, Err(AuthFailure::LockedOut { retry_after_secs: retry_after_secs(lockout) }));
+                    return;
+                }
+                self.verifying.insert(connection_id);
+                self.pending_logins.push(PendingLogin::new(connection_id, client_id, address, username, password, self.credentials.clone()));
+            }
+            Packet::ResumeSession { token: session_token } => {
+                let (reply_to, response) = match self.sessions.resume(&session_token) {
+                    Some(session) => {
+                        let current_token = self.clients.get(client_id).and_then(|client| client.token.clone());
+                        if current_token.as_ref() != Some(&session_token) {
+                            self.end_session(client_id);
+                        }
+                        let resumed_id = self.rebind_client(connection_id, client_id, &session, session_token.clone());
+                        info!("Client {}: resumed session of '{}'", resumed_id, session.username);
+                        (resumed_id, Packet::AuthResponse { token: session_token, failure: None })
+                    }
+                    None => {
+                        warn!("Client {}: tried to resume an unknown or expired session.", client_id);
+                        (client_id, Packet::auth_failure(AuthFailure::InvalidSession))
+                    }
+                };
+                queue_write(&mut self.events, reply_to, response);
+                let buffered_messages = self.clients.get_mut(reply_to).filter(|client| client.is_authenticated()).map(|client| std::mem::take(&mut client.message_buffer));
+                for message in buffered_messages.unwrap_or_default() {
+                    queue_write(&mut self.events, reply_to, message);
+                }
+            }
+            Packet::Ping | Packet::GameData(_) | Packet::Sequenced { .. } | Packet::ChatMessage { .. } if !self.has_valid_session(client_id) => {
+                warn!("Client {}: {:?} without a valid session, ignoring.", client_id, packet.data_type());
+            }
+            Packet::Disconnect { .. } => {
+                info!("Client {}: logged out.", client_id);
+                self.end_session(client_id);
+                self.close(connection_id);
+            }
+            Packet::Ping => {
+                queue_write(&mut self.events, client_id, Packet::Ping);
+            }
+            // The answer to our heartbeat, receiving it was the point.
+            Packet::Heartbeat => {}
+            Packet::ChatMessage { recipient, text, .. } => self.handle_chat(client_id, recipient, text),
+            Packet::GameData(game_data) => {
+                for (recipient, reply) in self.handle_game_data(client_id, game_data).unwrap_or_default() {
+                    self.events.push(Event::new(game_event(client_id, recipient, reply)));
+                }
+            }
+            Packet::Sequenced { epoch, sequence, packet } => {
+                let Some(username) = self.clients.get(client_id).and_then(|client| client.username.clone()) else {
+                    warn!("Client {}: sequenced packet before authentication, ignoring.", client_id);
+                    return;
+                };
+                match self.deliveries.check(&username, epoch, sequence) {
+                    Delivery::New => {
+                        let replies = match *packet {
+                            Packet::GameData(game_data) => self.handle_game_data(client_id, game_data),
+                            Packet::ChatMessage { recipient, text, .. } => {
+                                self.handle_chat(client_id, recipient, text);
+                                Some(Vec::new())
+                            }
+                            unexpected_value => {
+                                warn!("Unexpected sequenced value {:?}", unexpected_value);
+                                Some(Vec::new())
+                            }
+                        };
+                        let Some(replies) = replies else {
+                            return;
+                        };
+                        self.deliveries.record(&username, epoch, sequence);
+                        if let Some(client) = self.clients.get_mut(client_id) {
+                            client.delivery_epoch = Some(epoch);
+                        }
+                        for (recipient, reply) in replies {
+                            self.events.push(Event::new(game_event(client_id, recipient, reply)));
+                        }
+                    }
+                    Delivery::Duplicate => {
+                        debug!("Client {}: duplicate sequence {}, acknowledging again.", client_id, sequence);
+                    }
+                    Delivery::Gap => {
+                        debug!("Client {}: sequence {} arrived out of order, waiting for retransmission.", client_id, sequence);
+                        return;
+                    }
+                }
+                queue_write(&mut self.events, client_id, Packet::Ack { sequence });
+            }
+            unexpected_value => {
+                warn!("Unexpected value {:?}", unexpected_value);
+            }
+        }
+    }
+
+    // Locked out logins are refused before the password is checked.
+    fn authenticate_client(&mut self, client_id: usize, username: &str, address: IpAddr, verified: bool) -> Result<String, AuthFailure> {
+        if !verified {
+            return match self.login_guard.record_failure(username, address) {
+                Some(lockout) => {
+                    warn!("Locking out '{}' or {} for {:?} after repeated failures.", username, address, lockout);
+                    Err(AuthFailure::LockedOut { retry_after_secs: retry_after_secs(lockout) })
+                }
+                None => Err(AuthFailure::InvalidCredentials),
+            };
+        }
+        // Other guesses may have locked the name or address out while this one was being checked.
+        if let Some(lockout) = self.login_guard.locked_out(username, address) {
+            return Err(AuthFailure::LockedOut { retry_after_secs: retry_after_secs(lockout) });
+        }
+        self.login_guard.record_success(username);
+        Ok(self.sessions.create(username, client_id))
+    }
+
+    fn answer_login(&mut self, client_id: usize, username: String, result: Result<String, AuthFailure>) {
+        let (response, buffered_messages) = match result {
+            Ok(token) => {
+                info!("Client {}: authenticated as '{}'", client_id, username);
+                if let Some(other_client) = self.clients.by_username(&username).filter(|other_client| other_client.id != client_id) {
+                    info!("Client {}: '{}' is also logged in as client {}.", client_id, username, other_client.id);
+                }
+                // A fresh login picks up what was kept for a session it did not resume.
+                let buffered_messages = self.clients.resumable_by_username_mut(&username).map(|client| std::mem::take(&mut client.message_buffer));
+                self.clients.authenticate(client_id, username, token.clone());
+                (Packet::AuthResponse { token, failure: None }, buffered_messages.unwrap_or_default())
+            }
+            Err(failure) => {
+                warn!("Client {}: failed authentication as '{}': {}", client_id, username, failure);
+                (Packet::auth_failure(failure), Vec::new())
+            }
+        };
+        queue_write(&mut self.events, client_id, response);
+        for message in buffered_messages {
+            queue_write(&mut self.events, client_id, message);
+        }
+    }
+
+    // A session that ran out on a live connection ends it, the client logs in again when it reconnects.
+    fn has_valid_session(&mut self, client_id: usize) -> bool {
+        let Some(client) = self.clients.get_mut(client_id) else {
+            return false;
+        };
+        let Some(token) = &client.token else {
+            return false;
+        };
+        // Taken before validating, an expired session is gone afterwards.
+        let age = self.sessions.age(token).unwrap_or_default();
+        if self.sessions.validate(token).is_some() {
+            return true;
+        }
+        info!("Client {}: session expired after {}s, disconnecting.", client_id, age.as_secs());
+        client.token = None;
+        client.transition(ClientState::Accepted);
+        self.events.push(Event::new(EventType::Kick(client_id, DisconnectReason::SessionExpired)));
+        false
+    }
+
+    fn end_session(&mut self, client_id: usize) {
+        let Some(client) = self.clients.get_mut(client_id) else {
+            return;
+        };
+        if client.is_authenticated() {
+            client.transition(ClientState::Accepted);
+        }
+        if let Some(token) = client.token.take() {
+            self.sessions.invalidate(&token);
+        }
+    }
+
+    // Moves a resumed session onto the record its token was issued to, so the client keeps its id and pending messages.
+    // Returns the id the connection belongs to from now on.
+    fn rebind_client(&mut self, connection_id: ConnectionId, client_id: usize, session: &Session, session_token: String) -> usize {
+        let previous_id = session.client_id;
+        if previous_id == client_id || !self.clients.contains(previous_id) {
+            self.clients.authenticate(client_id, session.username.clone(), session_token);
+            return client_id;
+        }
+        let Some(new_client) = self.clients.remove(client_id) else {
+            return client_id;
+        };
+        let previous_connection = self.clients.attach(previous_id, connection_id);
+        if let Some(previous_client) = self.clients.get_mut(previous_id) {
+            previous_client.address = new_client.address;
+            previous_client.version = new_client.version;
+        }
+        self.clients.authenticate(previous_id, session.username.clone(), session_token);
+        if let Some(previous_connection) = previous_connection.filter(|previous_connection| *previous_connection != connection_id) {
+            info!("Client {}: resumed on another connection, closing the old one.", previous_id);
+            self.close(previous_connection);
+        }
+        previous_id
+    }
+
+    // None when the client has not authenticated yet.
+    fn handle_game_data(&mut self, client_id: usize, game_data: GameData) -> Option<Vec<(Recipient, GameData)>> {
+        let authenticated = self.clients.get(client_id).is_some_and(|client| client.is_authenticated());
+        if !authenticated {
+            warn!("Client {}: game data before authentication, ignoring.", client_id);
+            return None;
+        }
+        Some(self.game.on_game_data(client_id, game_data))
+    }
+
+    // Relays chat with the sender's name and the time it arrived. Direct messages for a user whose connection was lost
+    // wait on their record until the session is resumed, the sender is told when nobody can receive one.
+    fn handle_chat(&mut self, client_id: usize, recipient: Option<String>, text: String) {
+        let Some(sender) = self.clients.get(client_id).filter(|client| client.is_authenticated()).and_then(|client| client.username.clone()) else {
+            warn!("Client {}: chat before authentication, ignoring.", client_id);
+            return;
+        };
+        if text.trim().is_empty() || text.len() > MAX_CHAT_MESSAGE_LENGTH {
+            debug!("Client {}: refused a chat message of {} bytes.", client_id, text.len());
+            let notice = format!("messages have to be 1 to {} bytes long", MAX_CHAT_MESSAGE_LENGTH);
+            queue_write(&mut self.events, client_id, chat_notice(&sender, notice));
+            return;
+        }
+        let message = Packet::ChatMessage {
+            recipient: recipient.clone(),
+            sender: sender.clone(),
+            timestamp: unix_timestamp(),
+            text,
+        };
+        let Some(recipient) = recipient else {
+            debug!("Client {}: chat from '{}' to everyone.", client_id, sender);
+            self.events.push(Event::new(EventType::Broadcast(message)));
+            return;
+        };
+        debug!("Client {}: chat from '{}' to '{}'.", client_id, sender, recipient);
+        let online: Vec<(usize, bool)> = self
+            .clients
+            .connected()
+            .filter(|client| client.is_authenticated() && client.username.as_deref() == Some(recipient.as_str()))
+            .map(|client| (client.id, client.can_receive(&message)))
+            .collect();
+        let recipient_ids: Vec<usize> = online.iter().filter(|(_, can_receive)| *can_receive).map(|(id, _)| *id).collect();
+        // Logged in, but only on protocol versions without chat.
+        if recipient_ids.is_empty() && !online.is_empty() {
+            let notice = format!("'{}' is online with a client that cannot receive chat", recipient);
+            queue_write(&mut self.events, client_id, chat_notice(&sender, notice));
+            return;
+        }
+        if recipient_ids.is_empty() {
+            let Some(recipient_client) = self.clients.resumable_by_username_mut(&recipient) else {
+                queue_write(&mut self.events, client_id, chat_notice(&sender, format!("'{}' is not online", recipient)));
+                return;
+            };
+            if recipient_client.message_buffer.len() >= MAX_BUFFERED_MESSAGES {
+                recipient_client.message_buffer.remove(0);
+            }
+            recipient_client.message_buffer.push(message.clone());
+        }
+        for &recipient_id in &recipient_ids {
+            queue_write(&mut self.events, recipient_id, message.clone());
+        }
+        // The sender sees its own message the way the recipient got it.
+        if !recipient_ids.contains(&client_id) {
+            queue_write(&mut self.events, client_id, message);
+        }
+    }
+
+    // Resolves queued events against the client records into work for the connections.
+    fn process_events(&mut self) {
+        for event in std::mem::take(&mut self.events) {
+            match event.event_type {
+                EventType::SendTo(client_id, packet) => {
+                    if let Some(connection_id) = self.live_connection(client_id) {
+                        self.outgoing.push((connection_id, Outgoing::Send(packet)));
+                    }
+                }
+                EventType::Broadcast(packet) => self.broadcast(None, packet),
+                EventType::BroadcastExcept(client_id, packet) => self.broadcast(Some(client_id), packet),
+                EventType::Kick(client_id, reason) => {
+                    if let Some(connection_id) = self.live_connection(client_id) {
+                        self.outgoing.push((connection_id, Outgoing::Send(Packet::Disconnect { reason })));
+                        self.close(connection_id);
+                    }
+                }
+                EventType::Close(client_id) => {
+                    if let Some(connection_id) = self.live_connection(client_id) {
+                        self.close(connection_id);
+                    }
+                }
+            }
+        }
+    }
+
+    // The connection a client is on, None when it has none or it is already closing.
+    fn live_connection(&self, client_id: usize) -> Option<ConnectionId> {
+        let connection_id = self.clients.get(client_id)?.connection?;
+        (!self.closing.contains(&connection_id)).then_some(connection_id)
+    }
+
+    fn broadcast(&mut self, except: Option<usize>, packet: Packet) {
+        let connection_ids: Vec<ConnectionId> = self
+            .clients
+            .connected()
+            .filter(|client| client.is_authenticated() && Some(client.id) != except && client.can_receive(&packet))
+            .filter_map(|client| client.connection)
+            .filter(|connection_id| !self.closing.contains(connection_id))
+            .collect();
+        for connection_id in connection_ids {
+            self.outgoing.push((connection_id, Outgoing::Send(packet.clone())));
+        }
+    }
+}
+
+fn queue_write(events: &mut Vec<Event>, client_id: usize, packet: Packet) {
+    events.push(Event::new(EventType::SendTo(client_id, packet)));
+}
+
+fn chat_notice(username: &str, text: String) -> Packet {
+    Packet::ChatMessage {
+        recipient: Some(username.to_string()),
+        sender: String::new(),
+        timestamp: unix_timestamp(),
+        text,
+    }
+}
+
+fn unix_timestamp() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs())
+}
+
+fn game_event(sender_id: usize, recipient: Recipient, game_data: GameData) -> EventType {
+    let packet = Packet::GameData(game_data);
+    match recipient {
+        Recipient::Sender => EventType::SendTo(sender_id, packet),
+        Recipient::Client(client_id) => EventType::SendTo(client_id, packet),
+        Recipient::Everyone => EventType::Broadcast(packet),
+        Recipient::EveryoneElse => EventType::BroadcastExcept(sender_id, packet),
+    }
+}